@@ -1,32 +1,101 @@
 // NodeTypes: "Program", "NumericLiteral", "Identifier", "BinaryExp"
 
+use std::rc::Rc;
+
+use crate::span::Span;
+
 #[derive(Debug)]
 pub enum StatementOrExpression {
     Statement(Statement),
     Expression(Expression),
 }
 
+impl StatementOrExpression {
+    pub fn span(&self) -> Span {
+        match self {
+            StatementOrExpression::Statement(s) => s.span(),
+            StatementOrExpression::Expression(e) => e.span(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Statement {
     Program(Program),
     VariableDecleration(VariableDecleration),
+    FunctionDeclaration(FunctionDeclaration),
+    Return(ReturnStatement),
+    Block(BlockStatement),
+    If(IfStatement),
+    While(WhileStatement),
+    For(ForStatement),
+    Break(Span),
+    Continue(Span),
+    Import(ImportStatement),
+    Export(ExportDeclaration),
+    // placeholder for a statement that failed to parse
+    Error(Span),
+}
+
+impl Statement {
+    pub fn span(&self) -> Span {
+        match self {
+            Statement::Program(p) => p.span,
+            Statement::VariableDecleration(v) => v.span,
+            Statement::FunctionDeclaration(f) => f.span,
+            Statement::Return(r) => r.span,
+            Statement::Block(b) => b.span,
+            Statement::If(i) => i.span,
+            Statement::While(w) => w.span,
+            Statement::For(f) => f.span,
+            Statement::Break(span) => *span,
+            Statement::Continue(span) => *span,
+            Statement::Import(i) => i.span,
+            Statement::Export(e) => e.span,
+            Statement::Error(span) => *span,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum Expression {
     NumericLiteral(NumericLiteral),
+    StringLiteral(StringLiteral),
     Identifier(Identifier),
     Binary(Binary),
+    Unary(Unary),
     Assignment(Assignment),
     Property(Property),
     ObjectLiteral(ObjectLiteral),
+    ArrayLiteral(ArrayLiteral),
     Member(MemberExpr),
-    Call(CallExpr)
+    Call(CallExpr),
+    Function(FunctionExpr),
+}
+
+impl Expression {
+    pub fn span(&self) -> Span {
+        match self {
+            Expression::NumericLiteral(n) => n.span,
+            Expression::StringLiteral(s) => s.span,
+            Expression::Identifier(i) => i.span,
+            Expression::Binary(b) => b.span,
+            Expression::Unary(u) => u.span,
+            Expression::Assignment(a) => a.span,
+            Expression::Property(p) => p.span,
+            Expression::ObjectLiteral(o) => o.span,
+            Expression::ArrayLiteral(a) => a.span,
+            Expression::Member(m) => m.span,
+            Expression::Call(c) => c.span,
+            Expression::Function(f) => f.span,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Program {
     pub body: Vec<StatementOrExpression>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
@@ -34,16 +103,33 @@ pub struct Binary {
     pub left: Box<Expression>,
     pub operator: String,
     pub right: Box<Expression>,
+    pub span: Span,
 }
 
+// -x, !x
 #[derive(Debug)]
+pub struct Unary {
+    pub operator: String,
+    pub argument: Box<Expression>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
 pub struct Identifier {
     pub symbol: String,
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub struct NumericLiteral {
     pub value: f64,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct StringLiteral {
+    pub value: String,
+    pub span: Span,
 }
 
 #[derive(Debug)]
@@ -51,14 +137,16 @@ pub struct VariableDecleration {
     pub(crate) constant: bool,
     pub(crate) identifier: Identifier,
     pub(crate) value: Option<Expression>,
+    pub span: Span,
 }
 
 impl VariableDecleration {
-    pub fn new(identifier: String, value: Option<Expression>, constant: bool) -> Self {
+    pub fn new(identifier: Identifier, value: Option<Expression>, constant: bool, span: Span) -> Self {
         VariableDecleration {
             constant,
-            identifier: Identifier { symbol: identifier },
+            identifier,
             value,
+            span,
         }
     }
 }
@@ -67,13 +155,16 @@ impl VariableDecleration {
 pub struct Assignment {
     pub(crate) assignee: Box<Expression>,
     pub(crate) value: Box<Expression>,
+    pub span: Span,
 }
 
 impl Assignment {
     pub fn new(assignee: Expression, value: Expression) -> Self {
+        let span = assignee.span().to(value.span());
         Assignment {
             assignee: Box::new(assignee),
             value: Box::new(value),
+            span,
         }
     }
 }
@@ -82,13 +173,15 @@ impl Assignment {
 pub struct Property {
     pub(crate) key: String,
     pub(crate) value: Option<Box<Expression>>,
+    pub span: Span,
 }
 
 impl Property {
-    pub fn new(key: String, value: Expression) -> Self {
+    pub fn new(key: String, value: Expression, span: Span) -> Self {
         Property {
             key,
             value: Some(Box::new(value)),
+            span,
         }
     }
 }
@@ -96,11 +189,12 @@ impl Property {
 #[derive(Debug)]
 pub struct ObjectLiteral {
     pub(crate) properties: Vec<Property>,
+    pub span: Span,
 }
 
 impl ObjectLiteral {
-    pub fn new(properties: Vec<Property>) -> Self {
-        ObjectLiteral { properties }
+    pub fn new(properties: Vec<Property>, span: Span) -> Self {
+        ObjectLiteral { properties, span }
     }
 }
 
@@ -114,15 +208,122 @@ impl IntoIterator for ObjectLiteral {
     }
 }
 
+impl<'a> IntoIterator for &'a ObjectLiteral {
+    type Item = &'a Property;
+    type IntoIter = std::slice::Iter<'a, Property>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.properties.iter()
+    }
+}
+
+// [a, b, c]
+#[derive(Debug)]
+pub struct ArrayLiteral {
+    pub elements: Vec<Expression>,
+    pub span: Span,
+}
+
 #[derive(Debug)]
 pub struct CallExpr {
-    pub(crate) caller: Box<Expression>,
-    pub(crate) arguments: Vec<Expression>,
+    pub caller: Box<Expression>,
+    pub arguments: Vec<Expression>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub struct MemberExpr {
-    pub(crate) object: Box<Expression>,
-    pub(crate) property: Box<Expression>,
-    pub(crate) computed: bool,
-}
\ No newline at end of file
+    pub object: Box<Expression>,
+    pub property: Box<Expression>,
+    pub computed: bool,
+    pub span: Span,
+}
+
+// fn name(a, b) { ... }
+#[derive(Debug)]
+pub struct FunctionDeclaration {
+    pub name: Identifier,
+    pub parameters: Vec<Identifier>,
+    // shared so every function value created from this node can hold on to it
+    pub body: Rc<Vec<StatementOrExpression>>,
+    pub span: Span,
+}
+
+// fn (a, b) { ... } used as a value
+#[derive(Debug)]
+pub struct FunctionExpr {
+    pub parameters: Vec<Identifier>,
+    pub body: Rc<Vec<StatementOrExpression>>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct ReturnStatement {
+    pub value: Option<Expression>,
+    pub span: Span,
+}
+
+// { ... }, runs in its own scope
+#[derive(Debug)]
+pub struct BlockStatement {
+    pub body: Vec<StatementOrExpression>,
+    pub span: Span,
+}
+
+// if condition { ... } else if other { ... } else { ... }
+#[derive(Debug)]
+pub struct IfStatement {
+    pub condition: Expression,
+    pub consequent: BlockStatement,
+    // either another `If` for `else if`, or a `Block` for a plain `else`
+    pub alternate: Option<Box<Statement>>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct WhileStatement {
+    pub condition: Expression,
+    pub body: BlockStatement,
+    pub span: Span,
+}
+
+// for (init; condition; update) { ... }
+#[derive(Debug)]
+pub struct ForStatement {
+    pub init: Option<Box<StatementOrExpression>>,
+    pub condition: Option<Expression>,
+    pub update: Option<Expression>,
+    pub body: BlockStatement,
+    pub span: Span,
+}
+
+// import { a, b } from "./util.avii";
+// Only allowed at the top level. The module loader binds the names before the
+// program runs, evaluating the statement itself does nothing.
+#[derive(Debug)]
+pub struct ImportStatement {
+    pub names: Vec<Identifier>,
+    // as written, relative to the importing file
+    pub path: String,
+    pub path_span: Span,
+    pub span: Span,
+}
+
+// export let x = 1; / export const y = 2; / export fn f() {}
+#[derive(Debug)]
+pub struct ExportDeclaration {
+    // a `VariableDecleration` or `FunctionDeclaration`
+    pub declaration: Box<Statement>,
+    pub span: Span,
+}
+
+impl ExportDeclaration {
+    /// The name the declaration binds.
+    pub fn name(&self) -> &Identifier {
+        match self.declaration.as_ref() {
+            Statement::VariableDecleration(var) => &var.identifier,
+            Statement::FunctionDeclaration(decl) => &decl.name,
+            _ => unreachable!("the parser only exports declarations"),
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    interpreter::{RuntimeError, RuntimeVal},
+    span::Span,
+};
+
+/// Bounds on what an untrusted script may use, so a hostile one ends with an
+/// error instead of crashing the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// How many function calls may be active at once.
+    pub max_call_depth: usize,
+    /// How deeply statements and expressions may be nested in the source.
+    pub max_parse_depth: usize,
+    /// Bytes of strings, arrays and objects scripts may create in total, a
+    /// quota rather than a bound on memory in use: dropping a value doesn't
+    /// give its bytes back. Like fuel it has to be topped up, by setting the
+    /// limits again.
+    pub max_allocated_bytes: Option<usize>,
+    /// The longest source, in bytes, that will be parsed.
+    pub max_source_len: Option<usize>,
+}
+
+// The default depths keep the recursive parser and interpreter within the 8MB
+// stack of the main thread, even in debug builds. Hosts running scripts on
+// threads with smaller stacks should lower them.
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_call_depth: 256,
+            max_parse_depth: 128,
+            max_allocated_bytes: None,
+            max_source_len: None,
+        }
+    }
+}
+
+/// Stops a running script from another thread. Cloning the handle gives another
+/// handle to the same script.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    /// Makes the script end with [`RuntimeError::Interrupted`] at its next
+    /// step. If nothing is running, the next script that runs stops instead.
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+}
+
+/// How much a script may still run, shared by every scope of one global
+/// environment. Each evaluated expression or statement, or vm instruction,
+/// costs one unit of fuel.
+#[derive(Debug, Default)]
+pub struct Budget {
+    // None runs forever
+    fuel: Cell<Option<u64>>,
+    interrupt: InterruptHandle,
+    limits: Cell<Limits>,
+    call_depth: Cell<usize>,
+    // bytes allocated since the limits were set
+    allocated: Cell<usize>,
+}
+
+impl Budget {
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel.get()
+    }
+
+    pub fn set_fuel(&self, fuel: Option<u64>) {
+        self.fuel.set(fuel);
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits.get()
+    }
+
+    /// Replaces the limits and starts counting allocated bytes from zero again.
+    pub fn set_limits(&self, limits: Limits) {
+        self.limits.set(limits);
+        self.allocated.set(0);
+    }
+
+    /// The bytes of strings, arrays and objects created since the limits were set.
+    pub fn allocated(&self) -> usize {
+        self.allocated.get()
+    }
+
+    // takes one step of fuel, for the node at `span`
+    pub(crate) fn step(&self, span: Span) -> Result<(), RuntimeError> {
+        // the interrupt is used up by the script it stopped
+        if self.interrupt.is_interrupted() && self.interrupt.flag.swap(false, Ordering::Relaxed) {
+            return Err(RuntimeError::Interrupted(span));
+        }
+
+        match self.fuel.get() {
+            None => Ok(()),
+            Some(0) => Err(RuntimeError::FuelExhausted(span)),
+            Some(fuel) => {
+                self.fuel.set(Some(fuel - 1));
+                Ok(())
+            },
+        }
+    }
+
+    pub(crate) fn call_depth(&self) -> usize {
+        self.call_depth.get()
+    }
+
+    // restores the depth after a call that may have ended halfway through
+    pub(crate) fn set_call_depth(&self, depth: usize) {
+        self.call_depth.set(depth);
+    }
+
+    pub(crate) fn enter_call(&self, span: Span) -> Result<(), RuntimeError> {
+        let max = self.limits.get().max_call_depth;
+        if self.call_depth.get() >= max {
+            return Err(RuntimeError::CallDepthExceeded(max, span));
+        }
+        self.call_depth.set(self.call_depth.get() + 1);
+        Ok(())
+    }
+
+    pub(crate) fn exit_call(&self) {
+        self.call_depth.set(self.call_depth.get().saturating_sub(1));
+    }
+
+    pub(crate) fn allocate(&self, bytes: usize, span: Span) -> Result<(), RuntimeError> {
+        let allocated = self.allocated.get().saturating_add(bytes);
+        if let Some(max) = self.limits.get().max_allocated_bytes {
+            if allocated > max {
+                return Err(RuntimeError::AllocationLimitExceeded(max, span));
+            }
+        }
+        self.allocated.set(allocated);
+        Ok(())
+    }
+
+    // charges for a value that was just created
+    pub(crate) fn allocate_value(&self, value: &RuntimeVal, span: Span) -> Result<(), RuntimeError> {
+        self.allocate(size_of_new(value), span)
+    }
+}
+
+pub(crate) const VALUE_SIZE: usize = std::mem::size_of::<RuntimeVal>();
+
+// The bytes a freshly created value takes, without what its elements share
+// with other values.
+fn size_of_new(value: &RuntimeVal) -> usize {
+    match value {
+        RuntimeVal::StringVal(s) => s.len(),
+        RuntimeVal::ArrayVal(arr) => arr.borrow().len() * VALUE_SIZE,
+        RuntimeVal::ObjectVal(obj) => obj.borrow().keys().map(|key| key.len() + VALUE_SIZE).sum(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+    use crate::{
+        engine::{EngineError, ScriptFn},
+        Engine,
+    };
+
+    fn engines() -> [Engine; 2] {
+        [Engine::new(), Engine::new().with_vm()]
+    }
+
+    // The default limits are sized for the 8MB stack of the main thread, test
+    // threads get less.
+    fn on_main_sized_stack(test: impl FnOnce() + Send + 'static) {
+        thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(test)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    fn runtime_error(result: Result<RuntimeVal, EngineError>) -> RuntimeError {
+        match result {
+            Err(EngineError::Runtime(e)) => e,
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scripts_stop_when_the_fuel_runs_out() {
+        for mut engine in engines() {
+            engine.set_fuel(Some(1_000));
+            let error = runtime_error(engine.eval("while true {}"));
+            assert!(matches!(error, RuntimeError::FuelExhausted(_)), "{:?}", error);
+            assert_eq!(engine.fuel(), Some(0));
+
+            engine.set_fuel(Some(1_000));
+            assert_eq!(engine.eval("1 + 2").unwrap().to_string(), "3");
+            assert!(engine.fuel().is_some_and(|fuel| fuel < 1_000));
+
+            engine.set_fuel(None);
+            assert!(engine.eval("let i = 0; while i < 5000 { i = i + 1; }").is_ok());
+        }
+    }
+
+    #[test]
+    fn interrupt_stops_a_running_loop() {
+        for mut engine in engines() {
+            // fails the test instead of hanging it if the interrupt is lost
+            engine.set_fuel(Some(100_000_000));
+
+            let handle = engine.interrupt_handle();
+            let interrupter = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                handle.interrupt();
+            });
+            let error = runtime_error(engine.eval("while true {}"));
+            interrupter.join().unwrap();
+            assert!(matches!(error, RuntimeError::Interrupted(_)), "{:?}", error);
+
+            // the interrupt was used up by the script it stopped
+            assert!(engine.eval("1").is_ok());
+        }
+    }
+
+    #[test]
+    fn interrupt_while_idle_stops_the_next_script() {
+        for mut engine in engines() {
+            engine.interrupt_handle().interrupt();
+            let error = runtime_error(engine.eval("1 + 2"));
+            assert!(matches!(error, RuntimeError::Interrupted(_)), "{:?}", error);
+            assert!(engine.eval("1 + 2").is_ok());
+        }
+    }
+
+    #[test]
+    fn recursion_stops_at_the_call_depth_limit() {
+        on_main_sized_stack(|| {
+            for mut engine in engines() {
+                let error = runtime_error(engine.eval("fn r() { r() } r()"));
+                assert!(matches!(error, RuntimeError::CallDepthExceeded(256, _)), "{:?}", error);
+
+                engine.set_limits(Limits { max_call_depth: 10, ..Limits::default() });
+                engine.eval("fn down(n) { if n == 0 { return 0; } down(n - 1) }").unwrap();
+                assert!(engine.eval("down(5)").is_ok());
+                let error = runtime_error(engine.eval("down(20)"));
+                assert!(matches!(error, RuntimeError::CallDepthExceeded(10, _)), "{:?}", error);
+            }
+        });
+    }
+
+    #[test]
+    fn host_callbacks_count_towards_the_call_depth() {
+        on_main_sized_stack(|| {
+            for mut engine in engines() {
+                engine.register_fn("call", |f: ScriptFn| f.call(Vec::new()));
+                let error = runtime_error(engine.eval("fn r() { call(r) } r()"));
+                assert!(matches!(error, RuntimeError::CallDepthExceeded(256, _)), "{:?}", error);
+
+                // the depth unwound with the error
+                assert_eq!(engine.eval("call(fn () { 7 })").unwrap().to_string(), "7");
+            }
+        });
+    }
+
+    #[test]
+    fn allocations_stop_at_the_quota_until_it_is_topped_up() {
+        for mut engine in engines() {
+            let limits = Limits { max_allocated_bytes: Some(1_000), ..Limits::default() };
+            engine.set_limits(limits);
+
+            let error = runtime_error(engine.eval(r#"let s = ""; while true { s = s + "0123456789"; }"#));
+            assert!(matches!(error, RuntimeError::AllocationLimitExceeded(1_000, _)), "{:?}", error);
+            assert!(engine.globals().budget().allocated() <= 1_000);
+
+            // dropping the string gave nothing back, 100 more bytes don't fit
+            let hundred = format!(r#"s = ""; s + "{}""#, "0123456789".repeat(10));
+            let error = runtime_error(engine.eval(&hundred));
+            assert!(matches!(error, RuntimeError::AllocationLimitExceeded(..)), "{:?}", error);
+
+            engine.set_limits(limits);
+            assert_eq!(engine.globals().budget().allocated(), 0);
+            assert!(engine.eval(&hundred).is_ok());
+        }
+    }
+}
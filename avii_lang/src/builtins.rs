@@ -0,0 +1,284 @@
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    budget::VALUE_SIZE,
+    capabilities::Capability,
+    environment::Environment,
+    interpreter::{call_function, Array, NativeFn, RuntimeError, RuntimeVal},
+    span::Span,
+};
+
+// print(a, b, ...) writes its arguments separated by spaces, followed by a newline
+pub fn print(args: Vec<RuntimeVal>, _env: &mut Environment) -> Result<RuntimeVal, RuntimeError> {
+    let line = args
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    println!("{}", line);
+
+    Ok(RuntimeVal::NullVal)
+}
+
+// time() returns the seconds since the unix epoch
+pub fn time(_args: Vec<RuntimeVal>, _env: &mut Environment) -> Result<RuntimeVal, RuntimeError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0);
+
+    Ok(RuntimeVal::NumberVal(now))
+}
+
+// len(value) returns the number of properties of an object, elements of an array, or characters of a string
+pub fn len(args: Vec<RuntimeVal>, _env: &mut Environment) -> Result<RuntimeVal, RuntimeError> {
+    match args.first() {
+        Some(RuntimeVal::ObjectVal(obj)) => Ok(RuntimeVal::NumberVal(obj.borrow().len() as f64)),
+        Some(RuntimeVal::ArrayVal(arr)) => Ok(RuntimeVal::NumberVal(arr.borrow().len() as f64)),
+        Some(RuntimeVal::StringVal(s)) => Ok(RuntimeVal::NumberVal(s.chars().count() as f64)),
+        Some(other) => Err(RuntimeError::InvalidArgument(
+            format!("len() expects an object, array or string, got {}", other.type_name()),
+            Span::default(),
+        )),
+        None => Err(RuntimeError::InvalidArgument(
+            "len() expects 1 argument, got 0".to_string(),
+            Span::default(),
+        )),
+    }
+}
+
+// random() returns a number in [0, 1)
+pub fn random(_args: Vec<RuntimeVal>, _env: &mut Environment) -> Result<RuntimeVal, RuntimeError> {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+    }
+
+    // xorshift64*, good enough for games and shuffling, not for secrets
+    let next = STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    });
+
+    Ok(RuntimeVal::NumberVal((next >> 11) as f64 / (1u64 << 53) as f64))
+}
+
+// read_file(path) returns the contents of a text file
+pub fn read_file(args: Vec<RuntimeVal>, env: &mut Environment) -> Result<RuntimeVal, RuntimeError> {
+    let path = string_arg("read_file", "path", args.first())?;
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| RuntimeError::InvalidArgument(format!("read_file() could not read {}: {}", path, e), Span::default()))?;
+    allocated(RuntimeVal::string(&contents), env)
+}
+
+// write_file(path, contents) replaces the file with `contents`, creating it if needed
+pub fn write_file(args: Vec<RuntimeVal>, _env: &mut Environment) -> Result<RuntimeVal, RuntimeError> {
+    let path = string_arg("write_file", "path", args.first())?;
+    let contents = string_arg("write_file", "contents", args.get(1))?;
+    std::fs::write(&path, contents)
+        .map(|_| RuntimeVal::NullVal)
+        .map_err(|e| RuntimeError::InvalidArgument(format!("write_file() could not write {}: {}", path, e), Span::default()))
+}
+
+// env_var(name) returns the environment variable, or null when it isn't set
+pub fn env_var(args: Vec<RuntimeVal>, _env: &mut Environment) -> Result<RuntimeVal, RuntimeError> {
+    let name = string_arg("env_var", "name", args.first())?;
+    match std::env::var(&name) {
+        Ok(value) => Ok(RuntimeVal::string(&value)),
+        Err(_) => Ok(RuntimeVal::NullVal),
+    }
+}
+
+fn string_arg(function: &str, name: &str, arg: Option<&RuntimeVal>) -> Result<String, RuntimeError> {
+    match arg {
+        Some(RuntimeVal::StringVal(s)) => Ok(s.to_string()),
+        Some(other) => Err(RuntimeError::InvalidArgument(
+            format!("{}() expects {} to be a string, got {}", function, name, other.type_name()),
+            Span::default(),
+        )),
+        None => Err(RuntimeError::InvalidArgument(
+            format!("{}() expects {} to be a string, got nothing", function, name),
+            Span::default(),
+        )),
+    }
+}
+
+// Installs the builtins. The ones needing a capability the environment wasn't
+// granted are replaced by functions that fail with a permission error, so
+// scripts learn why instead of finding the name undefined.
+pub(crate) fn register(env: &mut Environment) {
+    define(env, NativeFn::new("print", print), Some(Capability::Stdout));
+    define(env, NativeFn::new("time", time), Some(Capability::Clock));
+    define(env, NativeFn::new("random", random), Some(Capability::Random));
+    define(env, NativeFn::new("read_file", read_file), Some(Capability::FsRead));
+    define(env, NativeFn::new("write_file", write_file), Some(Capability::FsWrite));
+    define(env, NativeFn::new("env_var", env_var), Some(Capability::EnvVars));
+    define(env, NativeFn::new("len", len), None);
+}
+
+fn define(env: &mut Environment, func: NativeFn, capability: Option<Capability>) {
+    let name = func.name.clone();
+
+    let func = match capability {
+        Some(capability) if !env.capabilities().contains(capability) => {
+            let function = name.clone();
+            NativeFn::new(&name, move |_args, _env| {
+                Err(RuntimeError::PermissionDenied {
+                    function: function.clone(),
+                    capability,
+                    span: Span::default(),
+                })
+            })
+        },
+        _ => func,
+    };
+
+    env.insert(&name, RuntimeVal::NativeFn(func));
+}
+
+/// Looks up a built-in array method, bound to `array`: `arr.push` evaluates to a
+/// function that pushes onto `arr`.
+pub(crate) fn array_method(array: &Array, name: &str) -> Option<NativeFn> {
+    let array = array.clone();
+
+    let method = match name {
+        // push(a, b, ...) appends its arguments and returns the new length
+        "push" => NativeFn::new("push", move |args, env| {
+            env.budget().allocate(args.len() * VALUE_SIZE, Span::default())?;
+            let mut values = array.borrow_mut();
+            values.extend(args);
+            Ok(RuntimeVal::NumberVal(values.len() as f64))
+        }),
+        // pop() removes and returns the last element, or null when empty
+        "pop" => NativeFn::new("pop", move |_args, _env| {
+            Ok(array.borrow_mut().pop().unwrap_or(RuntimeVal::NullVal))
+        }),
+        // slice(start, end) copies a range into a new array, negative bounds count from the end
+        "slice" => NativeFn::new("slice", move |args, env| {
+            let values = array.borrow();
+            let start = slice_bound(args.first(), 0, values.len())?;
+            let end = slice_bound(args.get(1), values.len(), values.len())?;
+            allocated(RuntimeVal::array(values[start..end.max(start)].to_vec()), env)
+        }),
+        // map(f) calls f(element, index) for every element and collects the results
+        "map" => NativeFn::new("map", move |args, env| {
+            let callback = callback_arg("map", &args)?;
+
+            // work on a copy, the callback is free to modify the array
+            let values = array.borrow().to_vec();
+            let mut mapped = Vec::with_capacity(values.len());
+            for (i, value) in values.into_iter().enumerate() {
+                mapped.push(call_function(callback.clone(), vec![value, RuntimeVal::NumberVal(i as f64)], env)?);
+            }
+            allocated(RuntimeVal::array(mapped), env)
+        }),
+        // filter(f) keeps the elements for which f(element, index) is truthy
+        "filter" => NativeFn::new("filter", move |args, env| {
+            let callback = callback_arg("filter", &args)?;
+
+            let values = array.borrow().to_vec();
+            let mut kept = Vec::new();
+            for (i, value) in values.into_iter().enumerate() {
+                let keep = call_function(callback.clone(), vec![value.clone(), RuntimeVal::NumberVal(i as f64)], env)?;
+                if keep.is_truthy() {
+                    kept.push(value);
+                }
+            }
+            allocated(RuntimeVal::array(kept), env)
+        }),
+        // reduce(f, initial) folds the array with f(accumulator, element, index), starting
+        // from the first element when no initial value is given
+        "reduce" => NativeFn::new("reduce", move |args, env| {
+            let callback = callback_arg("reduce", &args)?;
+
+            let values = array.borrow().to_vec();
+            let mut values = values.into_iter().enumerate();
+            let mut acc = match args.get(1) {
+                Some(initial) => initial.clone(),
+                None => match values.next() {
+                    Some((_, first)) => first,
+                    None => {
+                        return Err(RuntimeError::InvalidArgument(
+                            "reduce() of an empty array needs an initial value".to_string(),
+                            Span::default(),
+                        ))
+                    }
+                },
+            };
+
+            for (i, value) in values {
+                acc = call_function(callback.clone(), vec![acc, value, RuntimeVal::NumberVal(i as f64)], env)?;
+            }
+            Ok(acc)
+        }),
+        // join(separator) concatenates the elements, separated by "," unless given
+        "join" => NativeFn::new("join", move |args, env| {
+            let separator = match args.first() {
+                None => ",".to_string(),
+                Some(RuntimeVal::StringVal(s)) => s.to_string(),
+                Some(other) => {
+                    return Err(RuntimeError::InvalidArgument(
+                        format!("join() expects a string separator, got {}", other.type_name()),
+                        Span::default(),
+                    ))
+                }
+            };
+
+            let joined = array
+                .borrow()
+                .iter()
+                .map(|value| value.to_string())
+                .collect::<Vec<String>>()
+                .join(&separator);
+            allocated(RuntimeVal::string(&joined), env)
+        }),
+        _ => return None,
+    };
+
+    Some(method)
+}
+
+// charges a newly created value to the script's allocation quota
+fn allocated(value: RuntimeVal, env: &Environment) -> Result<RuntimeVal, RuntimeError> {
+    env.budget().allocate_value(&value, Span::default())?;
+    Ok(value)
+}
+
+// resolves a slice() bound to an index into `len` elements
+fn slice_bound(arg: Option<&RuntimeVal>, default: usize, len: usize) -> Result<usize, RuntimeError> {
+    match arg {
+        None | Some(RuntimeVal::NullVal) => Ok(default),
+        Some(RuntimeVal::NumberVal(n)) => {
+            let n = n.trunc();
+            let index = if n < 0.0 { len as f64 + n } else { n };
+            Ok(index.clamp(0.0, len as f64) as usize)
+        },
+        Some(other) => Err(RuntimeError::InvalidArgument(
+            format!("slice() expects numbers, got {}", other.type_name()),
+            Span::default(),
+        )),
+    }
+}
+
+fn callback_arg(method: &str, args: &[RuntimeVal]) -> Result<RuntimeVal, RuntimeError> {
+    match args.first() {
+        Some(callback @ (RuntimeVal::Function(_) | RuntimeVal::Closure(_) | RuntimeVal::NativeFn(_))) => Ok(callback.clone()),
+        Some(other) => Err(RuntimeError::InvalidArgument(
+            format!("{}() expects a function, got {}", method, other.type_name()),
+            Span::default(),
+        )),
+        None => Err(RuntimeError::InvalidArgument(
+            format!("{}() expects a function, got nothing", method),
+            Span::default(),
+        )),
+    }
+}
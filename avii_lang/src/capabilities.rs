@@ -0,0 +1,145 @@
+/// Something a builtin can do outside the script itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    FsRead,
+    FsWrite,
+    Clock,
+    Random,
+    EnvVars,
+    Stdout,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 6] = [
+        Capability::FsRead,
+        Capability::FsWrite,
+        Capability::Clock,
+        Capability::Random,
+        Capability::EnvVars,
+        Capability::Stdout,
+    ];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Capability::FsRead => "fs_read",
+            Capability::FsWrite => "fs_write",
+            Capability::Clock => "clock",
+            Capability::Random => "random",
+            Capability::EnvVars => "env_vars",
+            Capability::Stdout => "stdout",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The capabilities a script is granted. Builtins needing anything else are
+/// still defined, but fail with a permission error when called. Nothing is
+/// granted by default.
+///
+/// ```
+/// use avii_lang::capabilities::{Capabilities, Capability};
+///
+/// let caps = Capabilities::none().with(Capability::Stdout).with(Capability::Clock);
+/// assert!(caps.contains(Capability::Stdout));
+/// assert!(!caps.contains(Capability::FsWrite));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    bits: u8,
+}
+
+impl Capabilities {
+    pub fn none() -> Self {
+        Capabilities { bits: 0 }
+    }
+
+    pub fn all() -> Self {
+        Capability::ALL.into_iter().fold(Capabilities::none(), Capabilities::with)
+    }
+
+    pub fn with(mut self, capability: Capability) -> Self {
+        self.bits |= capability.bit();
+        self
+    }
+
+    pub fn without(mut self, capability: Capability) -> Self {
+        self.bits &= !capability.bit();
+        self
+    }
+
+    pub fn contains(&self, capability: Capability) -> bool {
+        self.bits & capability.bit() != 0
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities::none()
+    }
+}
+
+impl FromIterator<Capability> for Capabilities {
+    fn from_iter<I: IntoIterator<Item = Capability>>(iter: I) -> Self {
+        iter.into_iter().fold(Capabilities::none(), Capabilities::with)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::EngineError, interpreter::RuntimeError, Engine};
+
+    fn engines(capabilities: Capabilities) -> [Engine; 2] {
+        [Engine::with_capabilities(capabilities), Engine::with_capabilities(capabilities).with_vm()]
+    }
+
+    #[test]
+    fn builtins_without_their_capability_are_denied() {
+        for mut engine in engines(Capabilities::default()) {
+            match engine.eval("time()") {
+                Err(EngineError::Runtime(RuntimeError::PermissionDenied { function, capability, .. })) => {
+                    assert_eq!(function, "time");
+                    assert_eq!(capability, Capability::Clock);
+                },
+                other => panic!("expected a permission error, got {:?}", other),
+            }
+
+            // still defined, only calling it fails
+            assert_eq!(engine.eval("let t = time; 1").unwrap().to_string(), "1");
+        }
+    }
+
+    #[test]
+    fn only_the_granted_capabilities_are_usable() {
+        for mut engine in engines(Capabilities::none().with(Capability::Clock)) {
+            assert_eq!(engine.eval("time() > 0").unwrap().to_string(), "true");
+            let error = engine.eval("random()").unwrap_err();
+            assert_eq!(error.to_string(), "Permission denied: random() needs the random capability");
+        }
+    }
+
+    #[test]
+    fn builtins_needing_nothing_always_work() {
+        for mut engine in engines(Capabilities::none()) {
+            assert_eq!(engine.eval("len([1, 2, 3])").unwrap().to_string(), "3");
+        }
+    }
+
+    #[test]
+    fn sets_of_capabilities() {
+        let all = Capabilities::all();
+        assert!(Capability::ALL.iter().all(|&c| all.contains(c)));
+        assert!(Capability::ALL.iter().all(|&c| !Capabilities::default().contains(c)));
+
+        let some: Capabilities = [Capability::Stdout, Capability::EnvVars].into_iter().collect();
+        assert!(some.contains(Capability::EnvVars));
+        assert!(!some.without(Capability::EnvVars).contains(Capability::EnvVars));
+        assert!(some.without(Capability::EnvVars).contains(Capability::Stdout));
+    }
+}
@@ -0,0 +1,875 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    ast::{
+        Assignment, Binary, BlockStatement, Expression, ForStatement, Identifier, IfStatement, MemberExpr, Program,
+        Statement, StatementOrExpression, VariableDecleration, WhileStatement,
+    },
+    interpreter::{MemberSpans, RuntimeError, RuntimeVal},
+    span::Span,
+};
+
+// Operators that map onto `interpreter::eval_binary_expr`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl BinaryOp {
+    fn from_operator(operator: &str) -> Option<Self> {
+        let op = match operator {
+            "+" => BinaryOp::Add,
+            "-" => BinaryOp::Subtract,
+            "*" => BinaryOp::Multiply,
+            "/" => BinaryOp::Divide,
+            "%" => BinaryOp::Modulo,
+            "==" => BinaryOp::Equal,
+            "!=" => BinaryOp::NotEqual,
+            "<" => BinaryOp::Less,
+            "<=" => BinaryOp::LessEqual,
+            ">" => BinaryOp::Greater,
+            ">=" => BinaryOp::GreaterEqual,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Modulo => "%",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+        }
+    }
+}
+
+// Every statement and expression leaves exactly one value on the stack.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    // push constants[i]
+    Constant(u32),
+    Null,
+    Bool(bool),
+    Pop,
+
+    // globals live in the `Environment`, by the name in names[i]
+    GetGlobal(u32),
+    SetGlobal(u32),
+    DefineGlobal { name: u32, constant: bool },
+
+    // locals live in numbered slots of the call frame. A slot gets a fresh,
+    // undeclared variable every time its scope is entered.
+    NewLocal(u32),
+    InitLocal(u32),
+    GetLocal { slot: u32, name: u32 },
+    SetLocal { slot: u32, name: u32 },
+
+    // variables of enclosing scopes, captured when the closure was created
+    GetCapture { index: u32, name: u32 },
+    SetCapture { index: u32, name: u32 },
+
+    Binary(BinaryOp),
+    Not,
+    Negate,
+
+    // jump to an absolute instruction index, the conditional jumps pop the condition
+    Jump(u32),
+    JumpIfFalse(u32),
+    JumpIfTrue(u32),
+
+    // build a value out of the topmost stack entries: key/value pairs, elements,
+    // or the captures of functions[i]
+    Object(u32),
+    Array(u32),
+    Closure(u32),
+
+    // spans index `members`
+    GetProperty { computed: bool, spans: u32 },
+    SetProperty { computed: bool, spans: u32 },
+
+    Call(u32),
+    Return,
+
+    // raise errors[i]: mistakes the compiler can see, which the tree walker only
+    // reports once they're executed
+    Fail(u32),
+}
+
+/// The bytecode of one function, or of the program itself.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub(crate) code: Vec<Op>,
+    // source location of each instruction
+    pub(crate) spans: Vec<Span>,
+    pub(crate) constants: Vec<RuntimeVal>,
+    pub(crate) names: Vec<String>,
+    pub(crate) functions: Vec<Rc<FunctionProto>>,
+    pub(crate) members: Vec<MemberSpans>,
+    pub(crate) errors: Vec<RuntimeError>,
+    // where the divisor of each division or remainder is, division by zero points at it
+    pub(crate) divisors: HashMap<usize, Span>,
+    // number of local slots a frame running this chunk needs
+    pub(crate) slots: usize,
+    // how the function uses each of its captures
+    pub(crate) captures: Vec<CapturedVariable>,
+}
+
+// A compiled function, turned into a closure each time its definition runs
+#[derive(Debug)]
+pub(crate) struct FunctionProto {
+    pub(crate) name: Option<String>,
+    pub(crate) parameters: Vec<Identifier>,
+    pub(crate) captures: Vec<Capture>,
+    pub(crate) chunk: Rc<Chunk>,
+}
+
+// where a closure finds a captured variable when it's created
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Capture {
+    // a slot of the frame creating the closure
+    Local(u32),
+    // one of the creating function's own captures
+    Capture(u32),
+}
+
+// Where a closure looks instead while a captured variable isn't declared yet,
+// i.e. what the name meant before the declaration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Fallback {
+    // another capture of the same closure
+    Capture(u32),
+    Global,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CapturedVariable {
+    pub(crate) constant: bool,
+    // None for variables that are always declared by the time the closure exists
+    pub(crate) fallback: Option<Fallback>,
+}
+
+/// Compiles a program into bytecode for `vm::run`. The result can be run any
+/// number of times.
+pub fn compile(program: &Program) -> Rc<Chunk> {
+    let mut compiler = Compiler {
+        functions: vec![FunctionState::new(false)],
+    };
+
+    compiler.compile_body(&program.body);
+    compiler.emit(Op::Return, program.span);
+
+    let state = compiler.functions.pop().expect("the program's own state is never popped");
+    Rc::new(state.chunk)
+}
+
+struct Local {
+    name: String,
+    slot: u32,
+    constant: bool,
+    span: Span,
+    // Code in the same function only sees a local once its declaration ran,
+    // nested functions see everything declared in the enclosing blocks, since
+    // they usually run later.
+    declared: bool,
+}
+
+struct Scope {
+    locals: Vec<Local>,
+    // first slot used by this scope, freed again when it ends
+    start: u32,
+}
+
+struct CaptureInfo {
+    source: Capture,
+    constant: bool,
+    span: Span,
+    fallback: Option<Fallback>,
+}
+
+#[derive(Default)]
+struct LoopContext {
+    // jumps waiting to be pointed at the end of the loop, or the next iteration
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+struct FunctionState {
+    chunk: Chunk,
+    // empty at the top level of the program, where variables are globals
+    scopes: Vec<Scope>,
+    captures: Vec<CaptureInfo>,
+    loops: Vec<LoopContext>,
+    next_slot: u32,
+    is_function: bool,
+}
+
+impl FunctionState {
+    fn new(is_function: bool) -> Self {
+        FunctionState {
+            chunk: Chunk::default(),
+            scopes: Vec::new(),
+            captures: Vec::new(),
+            loops: Vec::new(),
+            next_slot: 0,
+            is_function,
+        }
+    }
+
+    // the innermost local called `name`, `direct` skips the ones not declared yet
+    fn find_local(&self, name: &str, direct: bool) -> Option<&Local> {
+        self.scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.locals.iter().rev())
+            .find(|local| local.name == name && (local.declared || !direct))
+    }
+
+    // every visible local called `name`, innermost first
+    fn locals_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Local> {
+        self.scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.locals.iter().rev())
+            .filter(move |local| local.name == name)
+    }
+}
+
+enum Variable {
+    Local { slot: u32, constant: bool, span: Span },
+    Capture { index: u32, constant: bool, span: Span },
+    Global,
+}
+
+struct Compiler {
+    // the function being compiled, and the ones it's nested in
+    functions: Vec<FunctionState>,
+}
+
+impl Compiler {
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().expect("there is always a function being compiled")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.current().chunk
+    }
+
+    fn emit(&mut self, op: Op, span: Span) -> usize {
+        let chunk = self.chunk();
+        chunk.code.push(op);
+        chunk.spans.push(span);
+        chunk.code.len() - 1
+    }
+
+    fn here(&mut self) -> u32 {
+        self.chunk().code.len() as u32
+    }
+
+    // points the jump at `at` to the next instruction
+    fn patch_jump(&mut self, at: usize) {
+        let target = self.here();
+        self.patch_jump_to(at, target);
+    }
+
+    fn patch_jump_to(&mut self, at: usize, target: u32) {
+        match &mut self.chunk().code[at] {
+            Op::Jump(t) | Op::JumpIfFalse(t) | Op::JumpIfTrue(t) => *t = target,
+            op => unreachable!("{:?} is not a jump", op),
+        }
+    }
+
+    fn constant(&mut self, value: RuntimeVal) -> u32 {
+        let constants = &mut self.chunk().constants;
+        constants.push(value);
+        (constants.len() - 1) as u32
+    }
+
+    fn name(&mut self, name: &str) -> u32 {
+        let names = &mut self.chunk().names;
+        match names.iter().position(|n| n == name) {
+            Some(index) => index as u32,
+            None => {
+                names.push(name.to_string());
+                (names.len() - 1) as u32
+            }
+        }
+    }
+
+    fn fail(&mut self, error: RuntimeError) {
+        let span = error.span();
+        let errors = &mut self.chunk().errors;
+        errors.push(error);
+        let index = (errors.len() - 1) as u32;
+        self.emit(Op::Fail(index), span);
+    }
+
+    fn is_global_scope(&self) -> bool {
+        self.functions.len() == 1 && self.functions[0].scopes.is_empty()
+    }
+
+    fn begin_scope(&mut self, body: &[StatementOrExpression], span: Span) {
+        let start = self.current().next_slot;
+        self.current().scopes.push(Scope { locals: Vec::new(), start });
+        self.predeclare(body, span);
+    }
+
+    fn end_scope(&mut self) {
+        let scope = self.current().scopes.pop().expect("scopes are balanced");
+        self.current().next_slot = scope.start;
+    }
+
+    // gives every variable declared directly in `body` a slot in the innermost scope
+    fn predeclare(&mut self, body: &[StatementOrExpression], span: Span) {
+        for node in body {
+            let (identifier, constant) = match node {
+                StatementOrExpression::Statement(Statement::VariableDecleration(var)) => (&var.identifier, var.constant),
+                StatementOrExpression::Statement(Statement::FunctionDeclaration(decl)) => (&decl.name, false),
+                _ => continue,
+            };
+
+            let exists = self
+                .current()
+                .scopes
+                .last()
+                .is_some_and(|scope| scope.locals.iter().any(|l| l.name == identifier.symbol));
+            if exists {
+                continue;
+            }
+
+            let slot = self.add_local(identifier, constant, false);
+            self.emit(Op::NewLocal(slot), span);
+        }
+    }
+
+    fn add_local(&mut self, identifier: &Identifier, constant: bool, declared: bool) -> u32 {
+        let state = self.current();
+        let slot = state.next_slot;
+        state.next_slot += 1;
+        state.chunk.slots = state.chunk.slots.max(state.next_slot as usize);
+
+        state.scopes.last_mut().expect("locals need a scope").locals.push(Local {
+            name: identifier.symbol.clone(),
+            slot,
+            constant,
+            span: identifier.span,
+            declared,
+        });
+        slot
+    }
+
+    // stores the value on top of the stack in the local `identifier` declares
+    fn define_local(&mut self, identifier: &Identifier, constant: bool) {
+        let scope = self.current().scopes.last_mut().expect("locals need a scope");
+
+        match scope.locals.iter_mut().find(|l| l.name == identifier.symbol) {
+            Some(local) if local.declared => {
+                let previous = Some(local.span);
+                self.fail(RuntimeError::AlreadyDefined {
+                    name: identifier.symbol.clone(),
+                    span: identifier.span,
+                    previous,
+                });
+            },
+            Some(local) => {
+                local.declared = true;
+                local.constant = constant;
+                local.span = identifier.span;
+                let slot = local.slot;
+                self.emit(Op::InitLocal(slot), identifier.span);
+            },
+            None => {
+                let slot = self.add_local(identifier, constant, true);
+                self.emit(Op::InitLocal(slot), identifier.span);
+            },
+        }
+    }
+
+    fn resolve(&mut self, name: &str) -> Variable {
+        let depth = self.functions.len() - 1;
+
+        if let Some(local) = self.functions[depth].find_local(name, true) {
+            return Variable::Local { slot: local.slot, constant: local.constant, span: local.span };
+        }
+
+        match self.resolve_capture(depth, name) {
+            Some(index) => {
+                let capture = &self.functions[depth].captures[index as usize];
+                // with a fallback, whether it's constant depends on which variable is declared
+                let constant = capture.constant && capture.fallback.is_none();
+                Variable::Capture { index, constant, span: capture.span }
+            },
+            None => Variable::Global,
+        }
+    }
+
+    // finds `name` in the functions enclosing functions[depth], capturing it
+    // through every function in between
+    fn resolve_capture(&mut self, depth: usize, name: &str) -> Option<u32> {
+        self.capture_local(depth, name, 0)
+    }
+
+    // Captures the `skip`th local called `name` of the function enclosing
+    // functions[depth], counting from the innermost. A local that may not be
+    // declared yet when the closure reads it falls back to the next one out,
+    // the way the tree walker finds whichever variable is declared at the time.
+    fn capture_local(&mut self, depth: usize, name: &str, skip: usize) -> Option<u32> {
+        if depth == 0 {
+            return None;
+        }
+
+        let local = self.functions[depth - 1]
+            .locals_named(name)
+            .nth(skip)
+            .map(|local| (local.slot, local.constant, local.span, local.declared));
+
+        let Some((slot, constant, span, declared)) = local else {
+            let index = self.capture_local(depth - 1, name, 0)?;
+            return Some(self.capture_outer(depth, index));
+        };
+
+        let fallback = match declared {
+            true => None,
+            false => match self.capture_local(depth, name, skip + 1) {
+                Some(index) => Some(Fallback::Capture(index)),
+                None => Some(Fallback::Global),
+            },
+        };
+        Some(self.add_capture(depth, CaptureInfo { source: Capture::Local(slot), constant, span, fallback }))
+    }
+
+    // captures the capture `index` of the function enclosing functions[depth],
+    // along with the ones it falls back to
+    fn capture_outer(&mut self, depth: usize, index: u32) -> u32 {
+        let outer = &self.functions[depth - 1].captures[index as usize];
+        let (constant, span, fallback) = (outer.constant, outer.span, outer.fallback);
+
+        let fallback = fallback.map(|fallback| match fallback {
+            Fallback::Capture(outer) => Fallback::Capture(self.capture_outer(depth, outer)),
+            Fallback::Global => Fallback::Global,
+        });
+        self.add_capture(depth, CaptureInfo { source: Capture::Capture(index), constant, span, fallback })
+    }
+
+    fn add_capture(&mut self, depth: usize, capture: CaptureInfo) -> u32 {
+        let captures = &mut self.functions[depth].captures;
+        if let Some(index) = captures.iter().position(|c| c.source == capture.source) {
+            return index as u32;
+        }
+        captures.push(capture);
+        (captures.len() - 1) as u32
+    }
+
+    fn compile_body(&mut self, body: &[StatementOrExpression]) {
+        if body.is_empty() {
+            self.emit(Op::Null, Span::default());
+        }
+
+        for (i, node) in body.iter().enumerate() {
+            // only the last statement's value is kept
+            if i > 0 {
+                self.emit(Op::Pop, Span::default());
+            }
+            self.compile_node(node);
+        }
+    }
+
+    fn compile_node(&mut self, node: &StatementOrExpression) {
+        match node {
+            StatementOrExpression::Statement(stmt) => self.compile_stmt(stmt),
+            StatementOrExpression::Expression(expr) => self.compile_expr(expr),
+        }
+    }
+
+    fn compile_stmt(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::Program(p) => self.compile_body(&p.body),
+            Statement::VariableDecleration(var) => self.compile_var_decleration(var),
+            Statement::FunctionDeclaration(decl) => {
+                let index = self.compile_function(Some(&decl.name.symbol), &decl.parameters, &decl.body);
+                self.emit(Op::Closure(index), decl.span);
+
+                if self.is_global_scope() {
+                    let name = self.name(&decl.name.symbol);
+                    self.emit(Op::DefineGlobal { name, constant: false }, decl.name.span);
+                } else {
+                    self.define_local(&decl.name, false);
+                }
+            },
+            Statement::Return(ret) => {
+                match &ret.value {
+                    Some(value) => self.compile_expr(value),
+                    None => {
+                        self.emit(Op::Null, ret.span);
+                    },
+                }
+
+                if self.current().is_function {
+                    self.emit(Op::Return, ret.span);
+                } else {
+                    self.fail(RuntimeError::ReturnOutsideFunction(ret.span));
+                }
+            },
+            Statement::Block(block) => self.compile_block(block),
+            Statement::If(stmt) => self.compile_if(stmt),
+            Statement::While(stmt) => self.compile_while(stmt),
+            Statement::For(stmt) => self.compile_for(stmt),
+            Statement::Break(span) => {
+                if self.current().loops.is_empty() {
+                    return self.fail(RuntimeError::BreakOutsideLoop(*span));
+                }
+                let jump = self.emit(Op::Jump(0), *span);
+                self.current().loops.last_mut().expect("checked above").breaks.push(jump);
+            },
+            Statement::Continue(span) => {
+                if self.current().loops.is_empty() {
+                    return self.fail(RuntimeError::ContinueOutsideLoop(*span));
+                }
+                let jump = self.emit(Op::Jump(0), *span);
+                self.current().loops.last_mut().expect("checked above").continues.push(jump);
+            },
+            // the module loader bound the imported names before the program started
+            Statement::Import(import) => {
+                self.emit(Op::Null, import.span);
+            },
+            Statement::Export(export) => self.compile_stmt(&export.declaration),
+            Statement::Error(span) => self.fail(RuntimeError::InvalidSyntax(*span)),
+        }
+    }
+
+    fn compile_var_decleration(&mut self, var: &VariableDecleration) {
+        match &var.value {
+            Some(value) => self.compile_expr(value),
+            None => {
+                self.emit(Op::Null, var.span);
+            },
+        }
+
+        if self.is_global_scope() {
+            let name = self.name(&var.identifier.symbol);
+            self.emit(Op::DefineGlobal { name, constant: var.constant }, var.identifier.span);
+        } else {
+            self.define_local(&var.identifier, var.constant);
+        }
+    }
+
+    // compiles a function body into its own chunk, returning its index in the current one
+    fn compile_function(&mut self, name: Option<&str>, parameters: &[Identifier], body: &[StatementOrExpression]) -> u32 {
+        self.functions.push(FunctionState::new(true));
+        self.current().scopes.push(Scope { locals: Vec::new(), start: 0 });
+
+        // parameters take the first slots, in order, the body shares their scope
+        for param in parameters {
+            let previous = self
+                .current()
+                .scopes[0]
+                .locals
+                .iter()
+                .find(|l| l.name == param.symbol)
+                .map(|l| l.span);
+
+            match previous {
+                Some(previous) => {
+                    let state = self.current();
+                    state.next_slot += 1;
+                    state.chunk.slots = state.chunk.slots.max(state.next_slot as usize);
+                    self.fail(RuntimeError::AlreadyDefined {
+                        name: param.symbol.clone(),
+                        span: param.span,
+                        previous: Some(previous),
+                    });
+                },
+                None => {
+                    self.add_local(param, false, true);
+                },
+            }
+        }
+
+        let span = body.first().map(|s| s.span()).unwrap_or_default();
+        self.predeclare(body, span);
+        self.compile_body(body);
+        self.emit(Op::Return, span);
+
+        let mut state = self.functions.pop().expect("pushed above");
+        state.chunk.captures = state
+            .captures
+            .iter()
+            .map(|c| CapturedVariable { constant: c.constant, fallback: c.fallback })
+            .collect();
+        let proto = FunctionProto {
+            name: name.map(|n| n.to_string()),
+            parameters: parameters.to_vec(),
+            captures: state.captures.iter().map(|c| c.source).collect(),
+            chunk: Rc::new(state.chunk),
+        };
+
+        let functions = &mut self.chunk().functions;
+        functions.push(Rc::new(proto));
+        (functions.len() - 1) as u32
+    }
+
+    fn compile_block(&mut self, block: &BlockStatement) {
+        self.begin_scope(&block.body, block.span);
+        self.compile_body(&block.body);
+        self.end_scope();
+    }
+
+    fn compile_if(&mut self, stmt: &IfStatement) {
+        self.compile_expr(&stmt.condition);
+        let to_alternate = self.emit(Op::JumpIfFalse(0), stmt.span);
+
+        self.compile_block(&stmt.consequent);
+        let to_end = self.emit(Op::Jump(0), stmt.span);
+
+        self.patch_jump(to_alternate);
+        match &stmt.alternate {
+            Some(alternate) => self.compile_stmt(alternate),
+            None => {
+                self.emit(Op::Null, stmt.span);
+            },
+        }
+        self.patch_jump(to_end);
+    }
+
+    fn compile_while(&mut self, stmt: &WhileStatement) {
+        let start = self.here();
+        self.compile_expr(&stmt.condition);
+        let exit = self.emit(Op::JumpIfFalse(0), stmt.span);
+
+        self.current().loops.push(LoopContext::default());
+        self.compile_block(&stmt.body);
+        self.emit(Op::Pop, stmt.span);
+        self.emit(Op::Jump(start), stmt.span);
+        let context = self.current().loops.pop().expect("pushed above");
+
+        self.patch_jump(exit);
+        self.finish_loop(context, start);
+        self.emit(Op::Null, stmt.span);
+    }
+
+    fn compile_for(&mut self, stmt: &ForStatement) {
+        // the initializer's variables live for the whole loop
+        let init = stmt.init.as_deref().map(std::slice::from_ref).unwrap_or_default();
+        self.begin_scope(init, stmt.span);
+
+        if let Some(init) = &stmt.init {
+            self.compile_node(init);
+            self.emit(Op::Pop, stmt.span);
+        }
+
+        let start = self.here();
+        let exit = stmt.condition.as_ref().map(|condition| {
+            self.compile_expr(condition);
+            self.emit(Op::JumpIfFalse(0), stmt.span)
+        });
+
+        self.current().loops.push(LoopContext::default());
+        self.compile_block(&stmt.body);
+        self.emit(Op::Pop, stmt.span);
+        let context = self.current().loops.pop().expect("pushed above");
+
+        let next = self.here();
+        if let Some(update) = &stmt.update {
+            self.compile_expr(update);
+            self.emit(Op::Pop, stmt.span);
+        }
+        self.emit(Op::Jump(start), stmt.span);
+
+        if let Some(exit) = exit {
+            self.patch_jump(exit);
+        }
+        self.finish_loop(context, next);
+        self.end_scope();
+        self.emit(Op::Null, stmt.span);
+    }
+
+    // breaks go to the next instruction, continues to `next_iteration`
+    fn finish_loop(&mut self, context: LoopContext, next_iteration: u32) {
+        for jump in context.breaks {
+            self.patch_jump(jump);
+        }
+        for jump in context.continues {
+            self.patch_jump_to(jump, next_iteration);
+        }
+    }
+
+    fn compile_expr(&mut self, expr: &Expression) {
+        match expr {
+            Expression::NumericLiteral(n) => {
+                let index = self.constant(RuntimeVal::NumberVal(n.value));
+                self.emit(Op::Constant(index), n.span);
+            },
+            Expression::StringLiteral(s) => {
+                let index = self.constant(RuntimeVal::string(&s.value));
+                self.emit(Op::Constant(index), s.span);
+            },
+            Expression::Identifier(ident) => self.compile_variable(&ident.symbol, ident.span),
+            Expression::Binary(b) if b.operator == "&&" || b.operator == "||" => self.compile_logical(b),
+            Expression::Binary(b) => {
+                self.compile_expr(&b.left);
+                self.compile_expr(&b.right);
+
+                match BinaryOp::from_operator(&b.operator) {
+                    Some(op) => {
+                        let at = self.emit(Op::Binary(op), b.span);
+                        if matches!(op, BinaryOp::Divide | BinaryOp::Modulo) {
+                            let divisor = b.right.span();
+                            self.chunk().divisors.insert(at, divisor);
+                        }
+                    },
+                    None => self.fail(RuntimeError::UnknownOperator(b.operator.clone(), b.span)),
+                }
+            },
+            Expression::Unary(u) => {
+                self.compile_expr(&u.argument);
+                match u.operator.as_str() {
+                    "!" => {
+                        self.emit(Op::Not, u.span);
+                    },
+                    "-" => {
+                        self.emit(Op::Negate, u.span);
+                    },
+                    op => self.fail(RuntimeError::UnknownOperator(op.to_string(), u.span)),
+                }
+            },
+            Expression::Assignment(a) => self.compile_assignment(a),
+            Expression::ObjectLiteral(obj) => {
+                for prop in obj {
+                    let key = self.constant(RuntimeVal::string(&prop.key));
+                    self.emit(Op::Constant(key), prop.span);
+
+                    match &prop.value {
+                        Some(value) => self.compile_expr(value),
+                        // { key } is short for { key: key }
+                        None => self.compile_variable(&prop.key, prop.span),
+                    }
+                }
+                self.emit(Op::Object(obj.properties.len() as u32), obj.span);
+            },
+            Expression::ArrayLiteral(arr) => {
+                for element in &arr.elements {
+                    self.compile_expr(element);
+                }
+                self.emit(Op::Array(arr.elements.len() as u32), arr.span);
+            },
+            Expression::Member(member) => {
+                self.compile_expr(&member.object);
+                self.compile_member_property(member);
+                let spans = self.member_spans(member);
+                self.emit(Op::GetProperty { computed: member.computed, spans }, member.span);
+            },
+            Expression::Call(call) => {
+                self.compile_expr(&call.caller);
+                for arg in &call.arguments {
+                    self.compile_expr(arg);
+                }
+                self.emit(Op::Call(call.arguments.len() as u32), call.span);
+            },
+            Expression::Function(f) => {
+                let index = self.compile_function(None, &f.parameters, &f.body);
+                self.emit(Op::Closure(index), f.span);
+            },
+            // properties only appear inside object literals
+            Expression::Property(p) => self.fail(RuntimeError::InvalidSyntax(p.span)),
+        }
+    }
+
+    fn compile_variable(&mut self, symbol: &str, span: Span) {
+        let name = self.name(symbol);
+        let op = match self.resolve(symbol) {
+            Variable::Local { slot, .. } => Op::GetLocal { slot, name },
+            Variable::Capture { index, .. } => Op::GetCapture { index, name },
+            Variable::Global => Op::GetGlobal(name),
+        };
+        self.emit(op, span);
+    }
+
+    // && and || only evaluate the right hand side when the left doesn't decide the result
+    fn compile_logical(&mut self, b: &Binary) {
+        let is_and = b.operator == "&&";
+        let short_circuit = |target| if is_and { Op::JumpIfFalse(target) } else { Op::JumpIfTrue(target) };
+
+        self.compile_expr(&b.left);
+        let left = self.emit(short_circuit(0), b.span);
+        self.compile_expr(&b.right);
+        let right = self.emit(short_circuit(0), b.span);
+
+        // neither side short circuited
+        self.emit(Op::Bool(is_and), b.span);
+        let to_end = self.emit(Op::Jump(0), b.span);
+
+        self.patch_jump(left);
+        self.patch_jump(right);
+        self.emit(Op::Bool(!is_and), b.span);
+        self.patch_jump(to_end);
+    }
+
+    fn compile_assignment(&mut self, a: &Assignment) {
+        match a.assignee.as_ref() {
+            Expression::Identifier(ident) => {
+                self.compile_expr(&a.value);
+
+                let name = self.name(&ident.symbol);
+                let op = match self.resolve(&ident.symbol) {
+                    Variable::Local { constant: true, span, .. } | Variable::Capture { constant: true, span, .. } => {
+                        return self.fail(RuntimeError::AssignToConstant {
+                            name: ident.symbol.clone(),
+                            span: a.span,
+                            declared: Some(span),
+                        });
+                    },
+                    Variable::Local { slot, .. } => Op::SetLocal { slot, name },
+                    Variable::Capture { index, .. } => Op::SetCapture { index, name },
+                    Variable::Global => Op::SetGlobal(name),
+                };
+                self.emit(op, a.span);
+            },
+            Expression::Member(member) => {
+                self.compile_expr(&a.value);
+                self.compile_expr(&member.object);
+                self.compile_member_property(member);
+                let spans = self.member_spans(member);
+                self.emit(Op::SetProperty { computed: member.computed, spans }, a.span);
+            },
+            other => self.fail(RuntimeError::InvalidAssignmentTarget(other.span())),
+        }
+    }
+
+    // `obj.key` pushes the key as a string, `obj[expr]` evaluates it
+    fn compile_member_property(&mut self, member: &MemberExpr) {
+        if member.computed {
+            return self.compile_expr(&member.property);
+        }
+
+        match member.property.as_ref() {
+            Expression::Identifier(ident) => {
+                let key = self.constant(RuntimeVal::string(&ident.symbol));
+                self.emit(Op::Constant(key), ident.span);
+            },
+            other => self.fail(RuntimeError::InvalidPropertyKey("expression".to_string(), other.span())),
+        }
+    }
+
+    fn member_spans(&mut self, member: &MemberExpr) -> u32 {
+        let members = &mut self.chunk().members;
+        members.push(MemberSpans::of(member));
+        (members.len() - 1) as u32
+    }
+}
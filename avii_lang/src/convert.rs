@@ -0,0 +1,503 @@
+//! Conversions between rust values and [`RuntimeVal`], so host code doesn't
+//! have to match on values by hand.
+//!
+//! Structs convert to and from objects with [`avii_object!`](crate::avii_object):
+//!
+//! ```
+//! use avii_lang::{avii_object, convert::{FromAvii, IntoAvii}};
+//!
+//! struct Point { x: f64, y: f64, label: Option<String> }
+//! avii_object!(Point { x, y, label });
+//!
+//! let value = Point { x: 1.0, y: 2.0, label: None }.into_avii();
+//! let point = Point::from_avii(value).unwrap();
+//! assert_eq!(point.y, 2.0);
+//! ```
+
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    interpreter::{NativeFn, Object, RuntimeError, RuntimeVal},
+    span::Span,
+};
+
+pub trait IntoAvii {
+    fn into_avii(self) -> RuntimeVal;
+}
+
+pub trait FromAvii: Sized {
+    fn from_avii(value: RuntimeVal) -> Result<Self, RuntimeError>;
+}
+
+fn mismatch(expected: impl Into<String>, found: &RuntimeVal) -> RuntimeError {
+    RuntimeError::TypeMismatch {
+        expected: expected.into(),
+        found: found.type_name().to_string(),
+        span: Span::default(),
+    }
+}
+
+impl IntoAvii for RuntimeVal {
+    fn into_avii(self) -> RuntimeVal {
+        self
+    }
+}
+
+impl FromAvii for RuntimeVal {
+    fn from_avii(value: RuntimeVal) -> Result<Self, RuntimeError> {
+        Ok(value)
+    }
+}
+
+// functions returning nothing give null to the script
+impl IntoAvii for () {
+    fn into_avii(self) -> RuntimeVal {
+        RuntimeVal::NullVal
+    }
+}
+
+impl FromAvii for () {
+    fn from_avii(value: RuntimeVal) -> Result<Self, RuntimeError> {
+        match value {
+            RuntimeVal::NullVal => Ok(()),
+            other => Err(mismatch("null", &other)),
+        }
+    }
+}
+
+impl IntoAvii for f64 {
+    fn into_avii(self) -> RuntimeVal {
+        RuntimeVal::NumberVal(self)
+    }
+}
+
+impl FromAvii for f64 {
+    fn from_avii(value: RuntimeVal) -> Result<Self, RuntimeError> {
+        match value {
+            RuntimeVal::NumberVal(n) => Ok(n),
+            other => Err(mismatch("number", &other)),
+        }
+    }
+}
+
+impl IntoAvii for f32 {
+    fn into_avii(self) -> RuntimeVal {
+        RuntimeVal::NumberVal(self as f64)
+    }
+}
+
+impl FromAvii for f32 {
+    fn from_avii(value: RuntimeVal) -> Result<Self, RuntimeError> {
+        f64::from_avii(value).map(|n| n as f32)
+    }
+}
+
+// Numbers are all f64, so integers only convert back when the number is whole
+// and fits the type. The upper bound is exclusive: `MAX as f64` rounds up to
+// the next power of two for 64 bit types, which does not fit.
+macro_rules! impl_integer {
+    (signed: $($ty:ty),*) => {$(
+        impl_integer!(@impl $ty, -(<$ty>::MIN as f64));
+    )*};
+    (unsigned: $($ty:ty),*) => {$(
+        impl_integer!(@impl $ty, 2f64.powi(<$ty>::BITS as i32));
+    )*};
+    (@impl $ty:ty, $end:expr) => {
+        impl IntoAvii for $ty {
+            fn into_avii(self) -> RuntimeVal {
+                RuntimeVal::NumberVal(self as f64)
+            }
+        }
+
+        impl FromAvii for $ty {
+            fn from_avii(value: RuntimeVal) -> Result<Self, RuntimeError> {
+                match value {
+                    RuntimeVal::NumberVal(n) if n.fract() == 0.0 && n >= <$ty>::MIN as f64 && n < $end => Ok(n as $ty),
+                    RuntimeVal::NumberVal(n) => Err(RuntimeError::TypeMismatch {
+                        expected: concat!("a whole number that fits in ", stringify!($ty)).to_string(),
+                        found: n.to_string(),
+                        span: Span::default(),
+                    }),
+                    other => Err(mismatch("number", &other)),
+                }
+            }
+        }
+    };
+}
+
+impl_integer!(signed: i8, i16, i32, i64, isize);
+impl_integer!(unsigned: u8, u16, u32, u64, usize);
+
+impl IntoAvii for bool {
+    fn into_avii(self) -> RuntimeVal {
+        RuntimeVal::BoolVal(self)
+    }
+}
+
+impl FromAvii for bool {
+    fn from_avii(value: RuntimeVal) -> Result<Self, RuntimeError> {
+        match value {
+            RuntimeVal::BoolVal(b) => Ok(b),
+            other => Err(mismatch("boolean", &other)),
+        }
+    }
+}
+
+impl IntoAvii for String {
+    fn into_avii(self) -> RuntimeVal {
+        RuntimeVal::StringVal(Rc::from(self))
+    }
+}
+
+impl IntoAvii for &str {
+    fn into_avii(self) -> RuntimeVal {
+        RuntimeVal::StringVal(Rc::from(self))
+    }
+}
+
+impl FromAvii for String {
+    fn from_avii(value: RuntimeVal) -> Result<Self, RuntimeError> {
+        match value {
+            RuntimeVal::StringVal(s) => Ok(s.to_string()),
+            other => Err(mismatch("string", &other)),
+        }
+    }
+}
+
+// None is null, so optional function arguments can be left out
+impl<T: IntoAvii> IntoAvii for Option<T> {
+    fn into_avii(self) -> RuntimeVal {
+        match self {
+            Some(value) => value.into_avii(),
+            None => RuntimeVal::NullVal,
+        }
+    }
+}
+
+impl<T: FromAvii> FromAvii for Option<T> {
+    fn from_avii(value: RuntimeVal) -> Result<Self, RuntimeError> {
+        match value {
+            RuntimeVal::NullVal => Ok(None),
+            other => T::from_avii(other).map(Some),
+        }
+    }
+}
+
+impl<T: IntoAvii> IntoAvii for Vec<T> {
+    fn into_avii(self) -> RuntimeVal {
+        RuntimeVal::array(self.into_iter().map(IntoAvii::into_avii).collect())
+    }
+}
+
+impl<T: FromAvii> FromAvii for Vec<T> {
+    fn from_avii(value: RuntimeVal) -> Result<Self, RuntimeError> {
+        match value {
+            RuntimeVal::ArrayVal(arr) => arr.borrow().iter().cloned().map(T::from_avii).collect(),
+            other => Err(mismatch("array", &other)),
+        }
+    }
+}
+
+impl<T: IntoAvii> IntoAvii for HashMap<String, T> {
+    fn into_avii(self) -> RuntimeVal {
+        RuntimeVal::object(self.into_iter().map(|(k, v)| (k, v.into_avii())).collect())
+    }
+}
+
+impl<T: FromAvii> FromAvii for HashMap<String, T> {
+    fn from_avii(value: RuntimeVal) -> Result<Self, RuntimeError> {
+        let object = expect_object(value)?;
+        let entries: Vec<(String, RuntimeVal)> = object.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        entries
+            .into_iter()
+            .map(|(key, value)| Ok((key.clone(), in_property(T::from_avii(value), &key)?)))
+            .collect()
+    }
+}
+
+// Tuples are arrays of exactly their length
+macro_rules! impl_tuple {
+    ($len:literal; $($name:ident),+) => {
+        impl<$($name: IntoAvii),+> IntoAvii for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn into_avii(self) -> RuntimeVal {
+                let ($($name,)+) = self;
+                RuntimeVal::array(vec![$($name.into_avii()),+])
+            }
+        }
+
+        impl<$($name: FromAvii),+> FromAvii for ($($name,)+) {
+            fn from_avii(value: RuntimeVal) -> Result<Self, RuntimeError> {
+                let elements = match value {
+                    RuntimeVal::ArrayVal(arr) if arr.borrow().len() == $len => arr.borrow().to_vec(),
+                    RuntimeVal::ArrayVal(arr) => {
+                        return Err(RuntimeError::TypeMismatch {
+                            expected: format!("an array of length {}", $len),
+                            found: format!("an array of length {}", arr.borrow().len()),
+                            span: Span::default(),
+                        })
+                    },
+                    other => return Err(mismatch("array", &other)),
+                };
+                let mut elements = elements.into_iter();
+                Ok(($($name::from_avii(elements.next().expect("length checked above"))?,)+))
+            }
+        }
+    };
+}
+
+impl_tuple!(1; A);
+impl_tuple!(2; A, B);
+impl_tuple!(3; A, B, C);
+impl_tuple!(4; A, B, C, D);
+impl_tuple!(5; A, B, C, D, E);
+impl_tuple!(6; A, B, C, D, E, F);
+
+/// The object handle inside `value`, used by conversions of structs.
+pub fn expect_object(value: RuntimeVal) -> Result<Object, RuntimeError> {
+    match value {
+        RuntimeVal::ObjectVal(obj) => Ok(obj),
+        other => Err(mismatch("object", &other)),
+    }
+}
+
+/// Converts the property `name` of `object`, a missing property is read as null.
+pub fn property<T: FromAvii>(object: &Object, name: &str) -> Result<T, RuntimeError> {
+    let value = object.borrow().get(name).cloned().unwrap_or(RuntimeVal::NullVal);
+    in_property(T::from_avii(value), name)
+}
+
+// says which property a conversion failed in
+fn in_property<T>(result: Result<T, RuntimeError>, name: &str) -> Result<T, RuntimeError> {
+    result.map_err(|e| match e {
+        RuntimeError::TypeMismatch { expected, found, span } => RuntimeError::TypeMismatch {
+            expected: format!("{} in property {}", expected, name),
+            found,
+            span,
+        },
+        other => other,
+    })
+}
+
+/// Implements [`IntoAvii`] and [`FromAvii`] for a struct with named fields,
+/// mapping it to an object with one property per listed field. Every field's
+/// type has to convert itself.
+#[macro_export]
+macro_rules! avii_object {
+    ($ty:ident { $($field:ident),* $(,)? }) => {
+        impl $crate::convert::IntoAvii for $ty {
+            fn into_avii(self) -> $crate::interpreter::RuntimeVal {
+                let mut map = ::std::collections::HashMap::new();
+                $(map.insert(stringify!($field).to_string(), $crate::convert::IntoAvii::into_avii(self.$field));)*
+                $crate::interpreter::RuntimeVal::object(map)
+            }
+        }
+
+        impl $crate::convert::FromAvii for $ty {
+            fn from_avii(
+                value: $crate::interpreter::RuntimeVal,
+            ) -> ::std::result::Result<Self, $crate::interpreter::RuntimeError> {
+                let object = $crate::convert::expect_object(value)?;
+                Ok($ty {
+                    $($field: $crate::convert::property(&object, stringify!($field))?,)*
+                })
+            }
+        }
+    };
+}
+
+/// What a function exposed to scripts can return: any convertible value, or a
+/// `Result` to raise an error in the script.
+pub trait IntoAviiResult {
+    fn into_avii_result(self) -> Result<RuntimeVal, RuntimeError>;
+}
+
+impl<T: IntoAvii> IntoAviiResult for T {
+    fn into_avii_result(self) -> Result<RuntimeVal, RuntimeError> {
+        Ok(self.into_avii())
+    }
+}
+
+impl<T: IntoAvii> IntoAviiResult for Result<T, RuntimeError> {
+    fn into_avii_result(self) -> Result<RuntimeVal, RuntimeError> {
+        self.map(IntoAvii::into_avii)
+    }
+}
+
+/// Rust functions that can be registered with
+/// [`Engine::register_fn`](crate::Engine::register_fn). `Args` only tells the
+/// implementations apart: it's the tuple of argument types for typed functions,
+/// or [`Raw`] for functions taking the arguments and environment as they are.
+pub trait IntoNativeFn<Args> {
+    fn into_native_fn(self, name: &str) -> NativeFn;
+}
+
+/// Marks functions of the same shape as the builtins:
+/// `Fn(Vec<RuntimeVal>, &mut Environment) -> Result<RuntimeVal, RuntimeError>`.
+pub struct Raw;
+
+impl<F> IntoNativeFn<Raw> for F
+where
+    F: Fn(Vec<RuntimeVal>, &mut crate::environment::Environment) -> Result<RuntimeVal, RuntimeError> + 'static,
+{
+    fn into_native_fn(self, name: &str) -> NativeFn {
+        NativeFn::new(name, self)
+    }
+}
+
+// converts the arguments of a typed function, missing ones are null
+fn argument<T: FromAvii>(args: &mut std::vec::IntoIter<RuntimeVal>, name: &str, position: usize) -> Result<T, RuntimeError> {
+    let value = args.next().unwrap_or(RuntimeVal::NullVal);
+    T::from_avii(value).map_err(|e| match e {
+        RuntimeError::TypeMismatch { expected, found, .. } => RuntimeError::InvalidArgument(
+            format!("{}() argument {}: expected {}, got {}", name, position, expected, found),
+            Span::default(),
+        ),
+        other => other,
+    })
+}
+
+macro_rules! impl_native_fn {
+    ($count:literal; $($arg:ident),*) => {
+        impl<Func, Ret, $($arg),*> IntoNativeFn<($($arg,)*)> for Func
+        where
+            Func: Fn($($arg),*) -> Ret + 'static,
+            Ret: IntoAviiResult,
+            $($arg: FromAvii,)*
+        {
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn into_native_fn(self, name: &str) -> NativeFn {
+                let fn_name = name.to_string();
+                NativeFn::new(name, move |args, _env| {
+                    if args.len() > $count {
+                        return Err(RuntimeError::InvalidArgument(
+                            format!("{}() expects {} argument{}, got {}", fn_name, $count, if $count == 1 { "" } else { "s" }, args.len()),
+                            Span::default(),
+                        ));
+                    }
+
+                    let mut args = args.into_iter();
+                    let mut position = 0;
+                    $(
+                        position += 1;
+                        let $arg = argument::<$arg>(&mut args, &fn_name, position)?;
+                    )*
+                    (self)($($arg),*).into_avii_result()
+                })
+            }
+        }
+    };
+}
+
+impl_native_fn!(0;);
+impl_native_fn!(1; A);
+impl_native_fn!(2; A, B);
+impl_native_fn!(3; A, B, C);
+impl_native_fn!(4; A, B, C, D);
+impl_native_fn!(5; A, B, C, D, E);
+impl_native_fn!(6; A, B, C, D, E, F);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::EngineError, Engine};
+
+    fn number(n: f64) -> RuntimeVal {
+        RuntimeVal::NumberVal(n)
+    }
+
+    fn message<T: std::fmt::Debug>(result: Result<T, RuntimeError>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn integers_must_be_whole_and_fit() {
+        assert_eq!(i32::from_avii(number(-7.0)).unwrap(), -7);
+        assert_eq!(u8::from_avii(number(255.0)).unwrap(), 255);
+        assert_eq!(i64::from_avii(number(-(2f64.powi(63)))).unwrap(), i64::MIN);
+
+        assert_eq!(message(u8::from_avii(number(256.0))), "Expected a whole number that fits in u8, got 256");
+        assert_eq!(message(u32::from_avii(number(-1.0))), "Expected a whole number that fits in u32, got -1");
+        assert_eq!(message(i32::from_avii(number(1.5))), "Expected a whole number that fits in i32, got 1.5");
+        // i64::MAX and u64::MAX round up to these as f64
+        assert!(i64::from_avii(number(2f64.powi(63))).is_err());
+        assert!(u64::from_avii(number(2f64.powi(64))).is_err());
+        assert!(usize::from_avii(number(f64::NAN)).is_err());
+        assert_eq!(message(i32::from_avii(RuntimeVal::string("1"))), "Expected number, got string");
+    }
+
+    #[test]
+    fn containers() {
+        let value = vec![1.0, 2.0].into_avii();
+        assert_eq!(Vec::<f64>::from_avii(value).unwrap(), [1.0, 2.0]);
+        assert_eq!(message(Vec::<f64>::from_avii(vec!["a"].into_avii())), "Expected number, got string");
+
+        assert_eq!(Option::<f64>::from_avii(RuntimeVal::NullVal).unwrap(), None);
+        assert_eq!(Option::<f64>::from_avii(number(3.0)).unwrap(), Some(3.0));
+
+        let map = HashMap::from([("a".to_string(), 1.0)]);
+        assert_eq!(HashMap::<String, f64>::from_avii(map.into_avii()).unwrap()["a"], 1.0);
+
+        let pair = (1.0, "b".to_string()).into_avii();
+        assert_eq!(<(f64, String)>::from_avii(pair).unwrap(), (1.0, "b".to_string()));
+        let triple = vec![1.0, 2.0, 3.0].into_avii();
+        assert_eq!(
+            message(<(f64, f64)>::from_avii(triple)),
+            "Expected an array of length 2, got an array of length 3"
+        );
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Point {
+        x: f64,
+        label: Option<String>,
+    }
+    crate::avii_object!(Point { x, label });
+
+    #[test]
+    fn structs() {
+        let point = Point { x: 1.0, label: Some("p".to_string()) };
+        let value = point.into_avii();
+        assert_eq!(value.to_string(), "{ label: \"p\", x: 1 }");
+        assert_eq!(Point::from_avii(value).unwrap(), Point { x: 1.0, label: Some("p".to_string()) });
+
+        let missing = RuntimeVal::object(HashMap::new());
+        assert_eq!(message(Point::from_avii(missing)), "Expected number in property x, got null");
+    }
+
+    fn eval_error(engine: &mut Engine, source: &str) -> String {
+        match engine.eval(source) {
+            Err(EngineError::Runtime(e)) => e.to_string(),
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn registered_functions_convert_their_arguments() {
+        for mut engine in [Engine::new(), Engine::new().with_vm()] {
+            engine.register_fn("clamp", |x: f64, max: Option<f64>| x.min(max.unwrap_or(1.0)));
+            engine.register_fn("repeat", |s: String, times: usize| s.repeat(times));
+            engine.register_fn("fail", || -> Result<f64, RuntimeError> {
+                Err(RuntimeError::InvalidArgument("no".to_string(), Span::default()))
+            });
+
+            assert_eq!(engine.eval("clamp(5, 3)").unwrap().to_string(), "3");
+            assert_eq!(engine.eval("clamp(5)").unwrap().to_string(), "1");
+            assert_eq!(engine.eval(r#"repeat("ab", 2)"#).unwrap().to_string(), "abab");
+
+            assert_eq!(
+                eval_error(&mut engine, r#"clamp("5")"#),
+                "clamp() argument 1: expected number, got string"
+            );
+            assert_eq!(
+                eval_error(&mut engine, r#"repeat("ab", 1.5)"#),
+                "repeat() argument 2: expected a whole number that fits in usize, got 1.5"
+            );
+            assert_eq!(
+                eval_error(&mut engine, "clamp(1, 2, 3)"),
+                "clamp() expects 2 arguments, got 3"
+            );
+            assert_eq!(eval_error(&mut engine, "fail()"), "no");
+        }
+    }
+}
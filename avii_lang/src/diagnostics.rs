@@ -0,0 +1,362 @@
+use std::fmt::Write;
+
+use crate::{
+    interpreter::RuntimeError,
+    lexer::LexError,
+    parser::ParseError,
+    resolver::{ResolveError, ResolveWarning},
+    span::Span,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    // primary labels are underlined with `^`, secondary ones with `-`
+    pub primary: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity,
+            message: message.into(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+            help: None,
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Diagnostic::new(Severity::Error, message)
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Diagnostic::new(Severity::Warning, message)
+    }
+
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label { span, message: message.into(), primary: true });
+        self
+    }
+
+    pub fn with_secondary_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label { span, message: message.into(), primary: false });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+
+    /// Renders the diagnostic rustc style:
+    ///
+    /// ```text
+    /// error: Cannot assign to constant y
+    ///  --> test.avii:3:1
+    ///   |
+    /// 2 | const y = 42;
+    ///   |       - constant declared here
+    /// 3 | y = 1;
+    ///   | ^^^^^ assigned here
+    ///   |
+    ///   = help: declare y with `let` if it needs to change
+    /// ```
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        let mut out = String::new();
+        let lines: Vec<&str> = source.lines().collect();
+
+        // labels without a real location (e.g. values defined by the host) can't be shown
+        let mut labels: Vec<&Label> = self.labels.iter().filter(|l| l.span.line > 0).collect();
+        labels.sort_by_key(|l| (l.span.line, l.span.column));
+
+        let gutter = labels
+            .iter()
+            .map(|l| l.span.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let pad = " ".repeat(gutter);
+
+        let _ = writeln!(out, "{}: {}", self.severity, self.message);
+
+        let location = self
+            .labels
+            .iter()
+            .find(|l| l.primary && l.span.line > 0)
+            .or(labels.first().copied());
+        let _ = match location {
+            Some(l) => writeln!(out, "{}--> {}:{}:{}", pad, file_name, l.span.line, l.span.column),
+            None => writeln!(out, "{}--> {}", pad, file_name),
+        };
+
+        if !labels.is_empty() {
+            let _ = writeln!(out, "{} |", pad);
+        }
+
+        let mut last_line: Option<usize> = None;
+        for label in &labels {
+            let line_no = label.span.line;
+            let text = lines.get(line_no - 1).copied().unwrap_or("");
+
+            if last_line != Some(line_no) {
+                if let Some(last) = last_line {
+                    if line_no > last + 1 {
+                        let _ = writeln!(out, "...");
+                    }
+                }
+                let _ = writeln!(out, "{:>width$} | {}", line_no, text, width = gutter);
+                last_line = Some(line_no);
+            }
+
+            // underline from the label's column until the end of the span, or the end of the line
+            // when the span continues onto the next one
+            let offset = label.span.column - 1;
+            let available = text.chars().count().saturating_sub(offset);
+            let width = source
+                .get(label.span.start..label.span.end)
+                .map(|s| s.lines().next().unwrap_or("").chars().count())
+                .unwrap_or(0)
+                .min(available)
+                .max(1);
+            let marker = if label.primary { "^" } else { "-" };
+
+            let _ = writeln!(
+                out,
+                "{} | {}{} {}",
+                pad,
+                " ".repeat(offset),
+                marker.repeat(width),
+                label.message
+            );
+        }
+
+        if !self.notes.is_empty() || self.help.is_some() {
+            if !labels.is_empty() {
+                let _ = writeln!(out, "{} |", pad);
+            }
+            for note in &self.notes {
+                let _ = writeln!(out, "{} = note: {}", pad, note);
+            }
+            if let Some(help) = &self.help {
+                let _ = writeln!(out, "{} = help: {}", pad, help);
+            }
+        }
+
+        out
+    }
+}
+
+impl From<&LexError> for Diagnostic {
+    fn from(e: &LexError) -> Self {
+        match e {
+            LexError::UnexpectedCharacter(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "unexpected character"),
+            LexError::UnterminatedString(span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "string starts here but is never closed")
+                .with_help("strings can't span multiple lines, use \\n for line breaks"),
+            LexError::InvalidEscape(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "unknown escape")
+                .with_note("supported escapes are \\n \\t \\r \\0 \\\\ \\\" \\' and \\u{...}"),
+            LexError::InvalidNumber(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "not a valid number")
+                .with_note("numbers look like 42, 3.14, .5, 1e-9, 0xFF, 0o17, 0b1010 or 1_000_000"),
+            LexError::UnterminatedComment(span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "comment starts here but is never closed")
+                .with_note("block comments nest, every /* needs its own */"),
+        }
+    }
+}
+
+impl From<&ParseError> for Diagnostic {
+    fn from(e: &ParseError) -> Self {
+        match e {
+            ParseError::Lex(e) => Diagnostic::from(e),
+            ParseError::UnexpectedToken { expected, span, .. } => Diagnostic::error(e.to_string())
+                .with_label(*span, format!("expected {:?} here", expected)),
+            ParseError::ExpectedExpression { span, .. } => Diagnostic::error(e.to_string())
+                .with_label(*span, "expected an expression"),
+            ParseError::ConstWithoutValue(name, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "constant declared without a value")
+                .with_help(format!("give it a value: `const {} = ...;`", name)),
+            ParseError::InvalidNumber(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "not a valid number"),
+            ParseError::TooDeeplyNested(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "nested too deeply here")
+                .with_help("move some of the inner code into functions or variables"),
+            ParseError::SourceTooLong { .. } => Diagnostic::error(e.to_string()),
+            ParseError::NotAtTopLevel(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "inside a block or function"),
+            ParseError::ExpectedKeyword { keyword, span, .. } => Diagnostic::error(e.to_string())
+                .with_label(*span, format!("expected `{}` here", keyword)),
+            ParseError::ExpectedDeclaration { span, .. } => Diagnostic::error(e.to_string())
+                .with_label(*span, "expected `let`, `const` or `fn` here"),
+        }
+    }
+}
+
+impl From<&RuntimeError> for Diagnostic {
+    fn from(e: &RuntimeError) -> Self {
+        match e {
+            RuntimeError::UndefinedVariable(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "not found in this scope"),
+            RuntimeError::AlreadyDefined { span, previous, .. } => {
+                let mut d = Diagnostic::error(e.to_string())
+                    .with_label(*span, "redefined here");
+                if let Some(previous) = previous {
+                    d = d.with_secondary_label(*previous, "first defined here");
+                }
+                d
+            },
+            RuntimeError::AssignToConstant { name, span, declared } => {
+                let mut d = Diagnostic::error(e.to_string())
+                    .with_label(*span, "assigned here");
+                if let Some(declared) = declared {
+                    d = d.with_secondary_label(*declared, "constant declared here");
+                }
+                d.with_help(format!("declare {} with `let` if it needs to change", name))
+            },
+            RuntimeError::InvalidAssignmentTarget(span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "cannot be assigned to"),
+            RuntimeError::DivisionByZero(span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "divisor evaluates to zero"),
+            RuntimeError::UnknownOperator(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "unsupported operator"),
+            RuntimeError::InvalidSyntax(span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "syntax error here"),
+            RuntimeError::NotCallable(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "called here"),
+            RuntimeError::InvalidArgument(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "in this call"),
+            RuntimeError::ReturnOutsideFunction(span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "not inside a function body"),
+            RuntimeError::MissingProperty(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "no such property"),
+            RuntimeError::NotAnObject(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "not an object"),
+            RuntimeError::InvalidPropertyKey(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "invalid property key"),
+            RuntimeError::IndexOutOfBounds(_, length, span) => {
+                let note = match length {
+                    0 => "there are no valid indices, it is empty".to_string(),
+                    _ => format!("valid indices are 0 to {}", length - 1),
+                };
+                Diagnostic::error(e.to_string())
+                    .with_label(*span, "index out of range")
+                    .with_note(note)
+            },
+            RuntimeError::InvalidOperands { span, .. } => Diagnostic::error(e.to_string())
+                .with_label(*span, "invalid operand types"),
+            RuntimeError::InvalidOperand { span, .. } => Diagnostic::error(e.to_string())
+                .with_label(*span, "invalid operand type"),
+            RuntimeError::BreakOutsideLoop(span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "not inside a loop body"),
+            RuntimeError::ContinueOutsideLoop(span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "not inside a loop body"),
+            RuntimeError::TypeMismatch { span, .. } => Diagnostic::error(e.to_string())
+                .with_label(*span, "wrong type"),
+            RuntimeError::Interrupted(span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "stopped here")
+                .with_note("the host interrupted the script"),
+            RuntimeError::FuelExhausted(span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "stopped here")
+                .with_note("the script used up the steps it was allowed to take"),
+            RuntimeError::CallDepthExceeded(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "one call too many")
+                .with_help("check for recursion that never stops"),
+            RuntimeError::AllocationLimitExceeded(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "allocated here")
+                .with_note("every byte allocated since the host set the limit counts, even for values already dropped"),
+            RuntimeError::PermissionDenied { capability, span, .. } => Diagnostic::error(e.to_string())
+                .with_label(*span, "not permitted")
+                .with_help(format!("the host has to grant the {} capability for this call to work", capability)),
+            RuntimeError::ModuleNotFound { span, .. } => Diagnostic::error(e.to_string())
+                .with_label(*span, "imported here")
+                .with_note("paths are relative to the importing file"),
+            RuntimeError::ImportCycle { span, .. } => Diagnostic::error(e.to_string())
+                .with_label(*span, "imported here")
+                .with_help("move what both modules need into a third module"),
+            RuntimeError::MissingExport { name, span, .. } => Diagnostic::error(e.to_string())
+                .with_label(*span, "not exported")
+                .with_help(format!("declare {} in the module with `export` in front", name)),
+            RuntimeError::ImportDenied { span, .. } => Diagnostic::error(e.to_string())
+                .with_label(*span, "imported here"),
+        }
+    }
+}
+
+impl From<&ResolveError> for Diagnostic {
+    fn from(e: &ResolveError) -> Self {
+        match e {
+            ResolveError::UndefinedVariable(_, span) => Diagnostic::error(e.to_string())
+                .with_label(*span, "not found in this scope"),
+            ResolveError::AlreadyDefined { span, previous, .. } => {
+                let mut d = Diagnostic::error(e.to_string())
+                    .with_label(*span, "redefined here");
+                if let Some(previous) = previous {
+                    d = d.with_secondary_label(*previous, "first defined here");
+                }
+                d
+            },
+            ResolveError::AssignToConstant { name, span, declared } => {
+                let mut d = Diagnostic::error(e.to_string())
+                    .with_label(*span, "assigned here");
+                if let Some(declared) = declared {
+                    d = d.with_secondary_label(*declared, "constant declared here");
+                }
+                d.with_help(format!("declare {} with `let` if it needs to change", name))
+            },
+            ResolveError::UsedBeforeDeclaration { name, span, used, declared } => Diagnostic::error(e.to_string())
+                .with_label(*span, format!("called here, before {} is declared", name))
+                .with_secondary_label(*used, "used in the function here")
+                .with_secondary_label(*declared, "declared here"),
+        }
+    }
+}
+
+impl From<&ResolveWarning> for Diagnostic {
+    fn from(w: &ResolveWarning) -> Self {
+        match w {
+            ResolveWarning::UnusedVariable(name, span) => Diagnostic::warning(w.to_string())
+                .with_label(*span, "declared here but never read")
+                .with_help(format!("if this is intentional, prefix it with an underscore: `_{}`", name)),
+            ResolveWarning::Shadowing { span, shadowed, .. } => {
+                let mut d = Diagnostic::warning(w.to_string())
+                    .with_label(*span, "shadows an outer variable");
+                if let Some(shadowed) = shadowed {
+                    d = d.with_secondary_label(*shadowed, "shadowed variable declared here");
+                }
+                d
+            },
+        }
+    }
+}
@@ -0,0 +1,430 @@
+use std::{
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    ast::{Program, Statement, StatementOrExpression},
+    budget::{InterruptHandle, Limits},
+    capabilities::{Capabilities, Capability},
+    compiler,
+    convert::{FromAvii, IntoAvii, IntoNativeFn},
+    diagnostics::Diagnostic,
+    environment::Environment,
+    interpreter::{self, call_function, RuntimeError, RuntimeVal},
+    module::{self, Module, ModuleLoader},
+    parser::{ParseError, Parser},
+    resolver::{self, ResolveError},
+    span::Span,
+    vm,
+};
+
+/// Everything that can go wrong running a script through an [`Engine`].
+#[derive(Debug)]
+pub enum EngineError {
+    Io(std::io::Error),
+    // every syntax error in the source, nothing ran
+    Parse(Vec<ParseError>),
+    // every variable the resolver found a problem with, nothing ran
+    Resolve(Vec<ResolveError>),
+    Runtime(RuntimeError),
+    // something went wrong in a module the script imported, `error` belongs to its source
+    Module {
+        path: PathBuf,
+        source: String,
+        error: Box<EngineError>,
+    },
+}
+
+impl EngineError {
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            EngineError::Io(e) => vec![Diagnostic::error(format!("Could not read file: {}", e))],
+            EngineError::Parse(errors) => errors.iter().map(Diagnostic::from).collect(),
+            EngineError::Resolve(errors) => errors.iter().map(Diagnostic::from).collect(),
+            EngineError::Runtime(e) => vec![Diagnostic::from(e)],
+            EngineError::Module { path, error, .. } => error
+                .diagnostics()
+                .into_iter()
+                .map(|d| d.with_note(format!("in module {}", path.display())))
+                .collect(),
+        }
+    }
+
+    /// Renders every diagnostic against the source that failed. Errors in an
+    /// imported module are rendered against that module's source instead.
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        if let EngineError::Module { path, source, error } = self {
+            return error.render(&path.display().to_string(), source);
+        }
+
+        self.diagnostics()
+            .iter()
+            .map(|d| d.render(file_name, source))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for EngineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineError::Io(e) => write!(f, "Could not read file: {}", e),
+            EngineError::Parse(errors) => {
+                let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}", messages.join("\n"))
+            },
+            EngineError::Resolve(errors) => {
+                let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}", messages.join("\n"))
+            },
+            EngineError::Runtime(e) => write!(f, "{}", e),
+            EngineError::Module { path, error, .. } => write!(f, "In module {}: {}", path.display(), error),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+impl From<std::io::Error> for EngineError {
+    fn from(e: std::io::Error) -> Self {
+        EngineError::Io(e)
+    }
+}
+
+impl From<ParseError> for EngineError {
+    fn from(e: ParseError) -> Self {
+        EngineError::Parse(vec![e])
+    }
+}
+
+impl From<RuntimeError> for EngineError {
+    fn from(e: RuntimeError) -> Self {
+        EngineError::Runtime(e)
+    }
+}
+
+/// Runs scripts for a host application. Globals persist between calls, so a
+/// function defined by one script can be called by the next.
+///
+/// ```no_run
+/// use avii_lang::Engine;
+///
+/// let mut engine = Engine::new();
+/// engine.set_global("limit", 3);
+/// engine.eval("fn double(x) { x * 2 }").unwrap();
+/// println!("{}", engine.eval("double(limit)").unwrap());
+/// ```
+pub struct Engine {
+    env: Environment,
+    use_vm: bool,
+    modules: ModuleLoader,
+    // scripts may only import modules inside it
+    module_root: Option<PathBuf>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    /// An engine with the builtins, running scripts on the tree walking
+    /// interpreter. Scripts get no capabilities, builtins that reach outside
+    /// the script fail until [`with_capabilities`](Engine::with_capabilities)
+    /// grants them.
+    pub fn new() -> Self {
+        Engine::with_capabilities(Capabilities::default())
+    }
+
+    /// An engine whose scripts may only use builtins needing `capabilities`.
+    pub fn with_capabilities(capabilities: Capabilities) -> Self {
+        Engine {
+            env: Environment::new(capabilities).with_default_scope(),
+            use_vm: false,
+            modules: ModuleLoader::default(),
+            module_root: None,
+        }
+    }
+
+    /// Compiles scripts to bytecode and runs them on the vm instead.
+    pub fn with_vm(mut self) -> Self {
+        self.use_vm = true;
+        self
+    }
+
+    /// Only lets scripts import `.avii` files inside `root`. Without a root,
+    /// imports stay inside the directory of the file given to
+    /// [`run_file`](Engine::run_file), or the working directory for
+    /// [`eval`](Engine::eval), so a script in `app/` can't import
+    /// `../lib/util.avii` until the root is set to their common parent.
+    /// Importing also needs the `fs_read` capability.
+    pub fn with_module_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.module_root = Some(root.into());
+        self
+    }
+
+    /// Runs `source` in the global scope and returns the value of its last
+    /// statement. Its imports are looked up relative to the working directory.
+    ///
+    /// Nothing runs if the source has syntax errors, or if the
+    /// [resolver](crate::resolver) finds a variable that is undefined,
+    /// defined twice, a constant being assigned or used before its
+    /// declaration. Its warnings are left to [`resolver::resolve`].
+    pub fn eval(&mut self, source: &str) -> Result<RuntimeVal, EngineError> {
+        let program = self.parse(source)?;
+        let mut env = self.env.clone();
+
+        if module::imports(&program).next().is_some() {
+            let dir = std::env::current_dir()?.canonicalize()?;
+            let root = self.module_root(&dir)?;
+            self.link(&program, &dir, &root, &mut env)?;
+        }
+
+        self.run(program, &mut env)
+    }
+
+    /// Runs the script at `path` in the global scope, its imports are looked
+    /// up relative to it. Unless [`with_module_root`](Engine::with_module_root)
+    /// says otherwise, only files in its directory and below can be imported.
+    pub fn run_file(&mut self, path: impl AsRef<Path>) -> Result<RuntimeVal, EngineError> {
+        let source = std::fs::read_to_string(&path)?;
+        let path = path.as_ref().canonicalize()?;
+        let program = self.parse(&source)?;
+        let mut env = self.env.clone();
+        let root = self.module_root(directory(&path))?;
+
+        // the script counts as loading, so a module importing it back is a cycle
+        self.modules.begin(path.clone(), Span::default())?;
+        let result = self.link(&program, directory(&path), &root, &mut env);
+        self.modules.end();
+        result?;
+
+        self.run(program, &mut env)
+    }
+
+    fn module_root(&self, default: &Path) -> Result<PathBuf, EngineError> {
+        Ok(self.module_root.as_deref().unwrap_or(default).canonicalize()?)
+    }
+
+    // parses `source` and checks its variables against the engine's globals
+    fn parse(&self, source: &str) -> Result<Program, EngineError> {
+        let (program, errors) = Parser::produce_ast_recovering_with_limits(source, &self.limits());
+        if !errors.is_empty() {
+            return Err(EngineError::Parse(errors));
+        }
+
+        let resolution = resolver::resolve(&program, &self.env);
+        if !resolution.errors.is_empty() {
+            return Err(EngineError::Resolve(resolution.errors));
+        }
+        Ok(program)
+    }
+
+    fn run(&self, program: Program, env: &mut Environment) -> Result<RuntimeVal, EngineError> {
+        let result = match self.use_vm {
+            true => vm::run(&compiler::compile(&program), env),
+            false => interpreter::evaluate(StatementOrExpression::Statement(Statement::Program(program)), env),
+        };
+        Ok(result?)
+    }
+
+    // Binds the names `program` imports in `env`, loading the modules they come
+    // from. Imported names are constants.
+    fn link(&mut self, program: &Program, dir: &Path, root: &Path, env: &mut Environment) -> Result<(), EngineError> {
+        for import in module::imports(program) {
+            if !self.env.capabilities().contains(Capability::FsRead) {
+                return Err(RuntimeError::ImportDenied {
+                    path: import.path.clone(),
+                    reason: format!("loading modules needs the {} capability", Capability::FsRead),
+                    span: import.path_span,
+                }
+                .into());
+            }
+
+            let path = module::resolve_path(dir, root, import)?;
+            let module = match self.modules.get(&path) {
+                Some(module) => module,
+                None => {
+                    self.modules.begin(path.clone(), import.path_span)?;
+                    let result = self.load(path, root);
+                    self.modules.end();
+                    result?
+                },
+            };
+
+            for name in &import.names {
+                let value = module.get(&name.symbol).ok_or_else(|| RuntimeError::MissingExport {
+                    name: name.symbol.clone(),
+                    module: import.path.clone(),
+                    span: name.span,
+                })?;
+
+                // importing the same thing again, e.g. in a later eval, changes nothing
+                if env.is_constant(&name.symbol) && env.get(&name.symbol).is_some_and(|existing| existing.equals(value)) {
+                    continue;
+                }
+                env.declare(&name.symbol, value.clone(), true, name.span)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Runs the module at `path` in its own global scope, on top of the
+    // engine's globals, and keeps what it exported. Exports are copied out once
+    // the module ran: an importer sees the values they had then, a later
+    // assignment in the module doesn't reach it. Arrays and objects are still
+    // shared, their contents can change.
+    fn load(&mut self, path: PathBuf, root: &Path) -> Result<Rc<Module>, EngineError> {
+        let source = std::fs::read_to_string(&path)?;
+        let mut env = Environment::new_with_parent(self.env.clone());
+
+        let result = self.parse(&source).and_then(|program| {
+            let names = module::exported_names(&program);
+            self.link(&program, directory(&path), root, &mut env)?;
+            self.run(program, &mut env)?;
+            Ok(names)
+        });
+
+        match result {
+            Ok(names) => {
+                let exports = names
+                    .into_iter()
+                    .filter_map(|name| env.get(&name).map(|value| (name, value)))
+                    .collect();
+                Ok(self.modules.insert(Module::new(path, exports)))
+            },
+            Err(error) => Err(EngineError::Module { path, source, error: Box::new(error) }),
+        }
+    }
+
+    /// Defines a global, replacing any existing one with the same name.
+    pub fn set_global(&mut self, name: &str, value: impl IntoAvii) {
+        self.env.insert(name, value.into_avii());
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<RuntimeVal> {
+        self.env.get(name)
+    }
+
+    /// Exposes a rust function to scripts as the global `name`. Its arguments
+    /// and return value are converted with [`FromAvii`](crate::convert::FromAvii)
+    /// and [`IntoAvii`], or it can take the raw arguments like the builtins do.
+    ///
+    /// ```no_run
+    /// # let mut engine = avii_lang::Engine::new();
+    /// engine.register_fn("clamp", |x: f64, max: Option<f64>| x.min(max.unwrap_or(1.0)));
+    /// ```
+    pub fn register_fn<Args>(&mut self, name: &str, func: impl IntoNativeFn<Args>) {
+        self.set_global(name, RuntimeVal::NativeFn(func.into_native_fn(name)));
+    }
+
+    /// A handle to the script function stored in the global `name`, which stays
+    /// valid across later calls to [`eval`](Engine::eval).
+    pub fn get_fn(&self, name: &str) -> Result<ScriptFn, RuntimeError> {
+        let value = self
+            .env
+            .get(name)
+            .ok_or_else(|| RuntimeError::UndefinedVariable(name.to_string(), Span::default()))?;
+        ScriptFn::new(value, Some(self.env.clone()))
+    }
+
+    /// Calls the function stored in the global `name`.
+    pub fn call_fn(&mut self, name: &str, args: Vec<RuntimeVal>) -> Result<RuntimeVal, RuntimeError> {
+        self.get_fn(name)?.call(args)
+    }
+
+    /// Limits how many steps scripts may take from now on, `None` lets them run
+    /// forever. Running out ends the script with [`RuntimeError::FuelExhausted`].
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.env.budget().set_fuel(fuel);
+    }
+
+    /// The fuel left, `None` if it's unlimited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.env.budget().fuel()
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.env.budget().limits()
+    }
+
+    /// Bounds what scripts may use from now on, hitting a limit ends the
+    /// script with an error. Allocated bytes are counted from zero again: the
+    /// allocation limit is a quota spent by every script the engine runs, so a
+    /// host running scripts over and over, e.g. once per event, has to call
+    /// this again to top it up.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.env.budget().set_limits(limits);
+    }
+
+    /// A handle that stops the running script from another thread.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.env.budget().interrupt_handle()
+    }
+
+    /// The global scope scripts run in.
+    pub fn globals(&self) -> &Environment {
+        &self.env
+    }
+}
+
+fn directory(file: &Path) -> &Path {
+    file.parent().unwrap_or(Path::new(""))
+}
+
+/// A function value taken out of a script, for the host to call later, e.g. an
+/// event handler. Cloning the handle shares the same function.
+///
+/// Native functions that come back as handles are called with the globals
+/// they were looked up in, or an empty scope if they arrived as an argument.
+#[derive(Debug, Clone)]
+pub struct ScriptFn {
+    func: RuntimeVal,
+    // the globals it was looked up in, script functions bring their own
+    globals: Option<Environment>,
+}
+
+impl ScriptFn {
+    fn new(func: RuntimeVal, globals: Option<Environment>) -> Result<Self, RuntimeError> {
+        match func {
+            RuntimeVal::Function(_) | RuntimeVal::Closure(_) | RuntimeVal::NativeFn(_) => Ok(ScriptFn { func, globals }),
+            other => Err(RuntimeError::NotCallable(other.type_name().to_string(), Span::default())),
+        }
+    }
+
+    pub fn call(&self, args: Vec<RuntimeVal>) -> Result<RuntimeVal, RuntimeError> {
+        let mut env = match (&self.globals, &self.func) {
+            (Some(globals), _) => globals.clone(),
+            (None, RuntimeVal::Function(func)) => func.env.clone(),
+            (None, RuntimeVal::Closure(closure)) => closure.globals.clone(),
+            (None, _) => Environment::new(Capabilities::none()),
+        };
+        call_function(self.func.clone(), args, &mut env)
+    }
+
+    /// The function as a script value.
+    pub fn value(&self) -> &RuntimeVal {
+        &self.func
+    }
+}
+
+// lets registered functions take callbacks: `on_tick(fn (dt) { ... })`
+impl FromAvii for ScriptFn {
+    fn from_avii(value: RuntimeVal) -> Result<Self, RuntimeError> {
+        match value {
+            RuntimeVal::Function(_) | RuntimeVal::Closure(_) | RuntimeVal::NativeFn(_) => ScriptFn::new(value, None),
+            other => Err(RuntimeError::TypeMismatch {
+                expected: "function".to_string(),
+                found: other.type_name().to_string(),
+                span: Span::default(),
+            }),
+        }
+    }
+}
+
+impl IntoAvii for ScriptFn {
+    fn into_avii(self) -> RuntimeVal {
+        self.func
+    }
+}
@@ -1,16 +1,40 @@
-use std::collections::HashMap;
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
-use crate::interpreter::RuntimeVal;
+use crate::budget::Budget;
+use crate::builtins;
+use crate::capabilities::Capabilities;
+use crate::interpreter::{RuntimeError, RuntimeVal};
+use crate::span::Span;
 
+// Environments are shared handles: cloning one gives another reference to the
+// same scope, which is what closures capture.
 #[derive(Debug, Clone)]
 pub struct Environment {
-    parent: Option<Box<Environment>>,
-    pub(crate) variables: HashMap<String, RuntimeVal>,
+    scope: Rc<RefCell<Scope>>,
+    // shared by the global scope and every scope created under it
+    budget: Rc<Budget>,
+    capabilities: Capabilities,
+}
+
+#[derive(Debug)]
+struct Scope {
+    parent: Option<Environment>,
+    variables: HashMap<String, RuntimeVal>,
     constants: Vec<String>,
+    // where each variable was declared, for diagnostics
+    declarations: HashMap<String, Span>,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new(Capabilities::default())
+    }
 }
 
 impl Environment {
-    pub fn new() -> Self {
+    /// A global scope for scripts granted `capabilities`, which decide the
+    /// builtins [`with_default_scope`](Environment::with_default_scope) installs.
+    pub fn new(capabilities: Capabilities) -> Self {
 
         // Default global environment
         let mut variables = HashMap::new();
@@ -18,79 +42,130 @@ impl Environment {
         variables.insert("false".to_string(), RuntimeVal::BoolVal(false));
         variables.insert("null".to_string(), RuntimeVal::NullVal);
 
-        Environment {
+        Environment::from_scope(Scope {
             parent: None,
             variables,
             constants: Vec::new(),
-        }
+            declarations: HashMap::new(),
+        }, Rc::default(), capabilities)
     }
 
     pub fn new_with_parent(parent: Environment) -> Self {
-        Environment {
-            parent: Some(Box::new(parent)),
+        let budget = parent.budget.clone();
+        let capabilities = parent.capabilities;
+        Environment::from_scope(Scope {
+            parent: Some(parent),
             variables: HashMap::new(),
             constants: Vec::new(),
+            declarations: HashMap::new(),
+        }, budget, capabilities)
+    }
+
+    fn from_scope(scope: Scope, budget: Rc<Budget>, capabilities: Capabilities) -> Self {
+        Environment {
+            scope: Rc::new(RefCell::new(scope)),
+            budget,
+            capabilities,
         }
     }
 
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// The fuel and interrupt handle of scripts running in this environment.
+    pub fn budget(&self) -> &Budget {
+        &self.budget
+    }
+
     pub fn with_default_scope(mut self) -> Self {
-        self.variables.insert("PI".to_string(), RuntimeVal::NumberVal(std::f64::consts::PI));
+        self.insert("PI", RuntimeVal::NumberVal(std::f64::consts::PI));
+        builtins::register(&mut self);
         self
     }
 
-    pub fn set(&mut self, symbol: &str, value: RuntimeVal, is_const: bool) -> RuntimeVal {
+    // define or overwrite a variable in this scope, bypassing the redefinition checks
+    pub(crate) fn insert(&mut self, symbol: &str, value: RuntimeVal) {
+        self.scope.borrow_mut().variables.insert(symbol.to_string(), value);
+    }
+
+    pub fn set(&mut self, symbol: &str, value: RuntimeVal, is_const: bool) -> Result<RuntimeVal, RuntimeError> {
+        self.declare(symbol, value, is_const, Span::default())
+    }
+
+    pub(crate) fn declare(&mut self, symbol: &str, value: RuntimeVal, is_const: bool, span: Span) -> Result<RuntimeVal, RuntimeError> {
+        let mut scope = self.scope.borrow_mut();
 
-        if self.variables.contains_key(symbol) {
-            panic!("Variable {} already defined", symbol);
+        if scope.variables.contains_key(symbol) {
+            return Err(RuntimeError::AlreadyDefined {
+                name: symbol.to_string(),
+                span,
+                previous: scope.declarations.get(symbol).copied(),
+            });
         }
 
-        self.variables.insert(symbol.to_string(), value.clone());
+        scope.variables.insert(symbol.to_string(), value.clone());
+        scope.declarations.insert(symbol.to_string(), span);
 
         if is_const {
-            self.constants.push(symbol.to_string());
+            scope.constants.push(symbol.to_string());
         }
 
-        value
+        Ok(value)
     }
 
-    pub fn assign(&mut self, symbol: &str, value: RuntimeVal) -> RuntimeVal {
+    pub fn assign(&mut self, symbol: &str, value: RuntimeVal) -> Result<RuntimeVal, RuntimeError> {
 
         let env = self.resolve(symbol);
 
         match env {
             Some(e) => {
                 let symbol = symbol.to_owned();
-
-                if e.constants.contains(&symbol) {
-                    panic!("Cannot assign to constant {}", symbol);
+                let mut scope = e.scope.borrow_mut();
+
+                if scope.constants.contains(&symbol) {
+                    return Err(RuntimeError::AssignToConstant {
+                        declared: scope.declarations.get(&symbol).copied(),
+                        name: symbol,
+                        span: Span::default(),
+                    });
                 }
 
-                e.variables.insert(symbol.clone(), value.clone());
+                scope.variables.insert(symbol, value.clone());
 
-                value
+                Ok(value)
             },
-            None => panic!("Variable {} not defined", symbol),
+            None => Err(RuntimeError::UndefinedVariable(symbol.to_string(), Span::default())),
         }
     }
 
-    pub fn resolve(&mut self, symbol: &str) -> Option<&mut Environment> {
-        if self.variables.contains_key(symbol) {
-            return Some(self);
+    pub fn resolve(&self, symbol: &str) -> Option<Environment> {
+        let scope = self.scope.borrow();
+
+        if scope.variables.contains_key(symbol) {
+            return Some(self.clone());
         }
 
-        match &mut self.parent {
+        match &scope.parent {
             Some(p) => p.resolve(symbol),
             None => None,
         }
     }
 
-    pub fn get(&mut self, symbol: &str) -> Option<RuntimeVal> {
+    pub(crate) fn is_constant(&self, symbol: &str) -> bool {
+        match self.resolve(symbol) {
+            Some(e) => e.scope.borrow().constants.iter().any(|c| c == symbol),
+            None => false,
+        }
+    }
+
+    pub fn get(&self, symbol: &str) -> Option<RuntimeVal> {
         let env = self.resolve(symbol);
 
         match env {
-            Some(e) => e.variables.get(symbol).map(|v| v.clone()),
+            Some(e) => e.scope.borrow().variables.get(symbol).cloned(),
             None => None,
         }
     }
 
-}
\ No newline at end of file
+}
@@ -1,146 +1,984 @@
-use std::collections::HashMap;
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
-use crate::{ast::{StatementOrExpression, Expression, Statement, Identifier, VariableDecleration, ObjectLiteral}, environment::Environment};
+use crate::{ast::{StatementOrExpression, Expression, Statement, Identifier, VariableDecleration, ObjectLiteral, ArrayLiteral, CallExpr, FunctionDeclaration, MemberExpr, Binary, Unary, BlockStatement, IfStatement, WhileStatement, ForStatement}, budget::{self, Budget}, builtins, capabilities::Capability, environment::Environment, span::Span, vm::{self, Closure}};
 
-#[derive(Debug, Clone)]
+pub type NativeFnCallback = dyn Fn(Vec<RuntimeVal>, &mut Environment) -> Result<RuntimeVal, RuntimeError>;
+
+// A function implemented in rust and exposed to scripts
+#[derive(Clone)]
+pub struct NativeFn {
+    pub name: String,
+    pub func: Rc<NativeFnCallback>,
+}
+
+impl NativeFn {
+    pub fn new<F>(name: &str, func: F) -> Self
+    where
+        F: Fn(Vec<RuntimeVal>, &mut Environment) -> Result<RuntimeVal, RuntimeError> + 'static,
+    {
+        NativeFn {
+            name: name.to_string(),
+            func: Rc::new(func),
+        }
+    }
+}
+
+impl std::fmt::Debug for NativeFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NativeFn({})", self.name)
+    }
+}
+
+// A function defined in a script, together with the scope it closes over
+pub struct Function {
+    pub name: Option<String>,
+    pub parameters: Vec<Identifier>,
+    pub body: Rc<Vec<StatementOrExpression>>,
+    pub env: Environment,
+}
+
+impl std::fmt::Debug for Function {
+    // the captured environment is left out, it usually contains the function itself
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let params: Vec<&str> = self.parameters.iter().map(|p| p.symbol.as_str()).collect();
+        write!(f, "Function({}({}))", self.name.as_deref().unwrap_or("<anonymous>"), params.join(", "))
+    }
+}
+
+// Objects are reference types: copying the value copies the handle, so every
+// copy sees the same properties.
+pub type Object = Rc<RefCell<Properties>>;
+
+// Arrays are shared the same way
+pub type Array = Rc<RefCell<Elements>>;
+
+/// The properties of an object, used like the map it wraps.
+#[derive(Debug, Clone, Default)]
+pub struct Properties(pub HashMap<String, RuntimeVal>);
+
+/// The elements of an array, used like the vector it wraps.
+#[derive(Debug, Clone, Default)]
+pub struct Elements(pub Vec<RuntimeVal>);
+
+impl std::ops::Deref for Properties {
+    type Target = HashMap<String, RuntimeVal>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for Properties {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl std::ops::Deref for Elements {
+    type Target = Vec<RuntimeVal>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for Elements {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for Properties {
+    fn drop(&mut self) {
+        let nested = self
+            .0
+            .drain()
+            .map(|(_, value)| value)
+            .filter(|value| matches!(value, RuntimeVal::ArrayVal(_) | RuntimeVal::ObjectVal(_)));
+        release(nested.collect());
+    }
+}
+
+impl Drop for Elements {
+    fn drop(&mut self) {
+        release(std::mem::take(&mut self.0));
+    }
+}
+
+// Dropping a value nested thousands of levels deep would recurse once per
+// level and overflow the stack, so the arrays and objects only this value
+// holds on to are taken apart in a loop instead.
+fn release(mut pending: Vec<RuntimeVal>) {
+    while let Some(value) = pending.pop() {
+        match value {
+            RuntimeVal::ArrayVal(arr) => {
+                if let Ok(cell) = Rc::try_unwrap(arr) {
+                    pending.append(&mut cell.into_inner().0);
+                }
+            },
+            RuntimeVal::ObjectVal(obj) => {
+                if let Ok(cell) = Rc::try_unwrap(obj) {
+                    pending.extend(cell.into_inner().0.drain().map(|(_, value)| value));
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+#[derive(Clone)]
 pub enum RuntimeVal {
     NumberVal(f64),
     BoolVal(bool),
-    ObjectVal(HashMap<String, RuntimeVal>),
+    StringVal(Rc<str>),
+    ObjectVal(Object),
+    ArrayVal(Array),
+    NativeFn(NativeFn),
+    Function(Rc<Function>),
+    // a function compiled for the bytecode vm
+    Closure(Rc<Closure>),
     NullVal,
 }
 
-fn eval_numeric_binary_expr(left: f64, right: f64, op: &str) -> RuntimeVal {
-    match op {
-        "+" => RuntimeVal::NumberVal(left + right),
-        "-" => RuntimeVal::NumberVal(left - right),
-        "*" => RuntimeVal::NumberVal(left * right),
-        "/" => {
-            if right == 0.0 {
-                panic!("Division by zero");
-            }
-            RuntimeVal::NumberVal(left / right)
-        },
-        "%" => RuntimeVal::NumberVal(left % right),
-        _ => panic!("Unknown operator {}", op),
+impl RuntimeVal {
+    pub fn string(value: &str) -> Self {
+        RuntimeVal::StringVal(Rc::from(value))
+    }
+
+    pub fn object(map: HashMap<String, RuntimeVal>) -> Self {
+        RuntimeVal::ObjectVal(Rc::new(RefCell::new(Properties(map))))
+    }
+
+    pub fn array(values: Vec<RuntimeVal>) -> Self {
+        RuntimeVal::ArrayVal(Rc::new(RefCell::new(Elements(values))))
+    }
+
+    // false, null, 0, NaN and "" are falsy, everything else is truthy
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            RuntimeVal::BoolVal(b) => *b,
+            RuntimeVal::NullVal => false,
+            RuntimeVal::NumberVal(n) => *n != 0.0 && !n.is_nan(),
+            RuntimeVal::StringVal(s) => !s.is_empty(),
+            _ => true,
+        }
+    }
+
+    // values of different types are never equal, objects, arrays and functions compare by identity
+    pub fn equals(&self, other: &RuntimeVal) -> bool {
+        match (self, other) {
+            (RuntimeVal::NumberVal(a), RuntimeVal::NumberVal(b)) => a == b,
+            (RuntimeVal::BoolVal(a), RuntimeVal::BoolVal(b)) => a == b,
+            (RuntimeVal::StringVal(a), RuntimeVal::StringVal(b)) => a == b,
+            (RuntimeVal::ObjectVal(a), RuntimeVal::ObjectVal(b)) => Rc::ptr_eq(a, b),
+            (RuntimeVal::ArrayVal(a), RuntimeVal::ArrayVal(b)) => Rc::ptr_eq(a, b),
+            (RuntimeVal::Function(a), RuntimeVal::Function(b)) => Rc::ptr_eq(a, b),
+            (RuntimeVal::Closure(a), RuntimeVal::Closure(b)) => Rc::ptr_eq(a, b),
+            (RuntimeVal::NativeFn(a), RuntimeVal::NativeFn(b)) => Rc::ptr_eq(&a.func, &b.func),
+            (RuntimeVal::NullVal, RuntimeVal::NullVal) => true,
+            _ => false,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            RuntimeVal::NumberVal(_) => "number",
+            RuntimeVal::BoolVal(_) => "boolean",
+            RuntimeVal::StringVal(_) => "string",
+            RuntimeVal::ObjectVal(_) => "object",
+            RuntimeVal::ArrayVal(_) => "array",
+            RuntimeVal::NativeFn(_) => "function",
+            RuntimeVal::Function(_) => "function",
+            RuntimeVal::Closure(_) => "function",
+            RuntimeVal::NullVal => "null",
+        }
+    }
+
+    // `seen` holds the objects and arrays currently being printed, so cycles print as
+    // [Circular] instead of recursing forever, and values nested too deeply print
+    // as [...] instead of overflowing the stack
+    fn fmt_nested(&self, f: &mut std::fmt::Formatter<'_>, seen: &mut Vec<*const ()>) -> std::fmt::Result {
+        match self {
+            RuntimeVal::NumberVal(n) => write!(f, "{}", n),
+            RuntimeVal::BoolVal(b) => write!(f, "{}", b),
+            RuntimeVal::StringVal(s) => write!(f, "{}", s),
+            RuntimeVal::ObjectVal(obj) => {
+                let ptr = Rc::as_ptr(obj) as *const ();
+                if seen.contains(&ptr) {
+                    return write!(f, "[Circular]");
+                }
+                if seen.len() >= MAX_PRINT_DEPTH {
+                    return write!(f, "{{...}}");
+                }
+                seen.push(ptr);
+
+                let map = obj.borrow();
+                let mut keys: Vec<&String> = map.keys().collect();
+                keys.sort();
+
+                write!(f, "{{")?;
+                for (i, key) in keys.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, " {}: ", key)?;
+                    map[*key].fmt_element(f, seen)?;
+                }
+
+                seen.pop();
+                if keys.is_empty() {
+                    write!(f, "}}")
+                } else {
+                    write!(f, " }}")
+                }
+            },
+            RuntimeVal::ArrayVal(arr) => {
+                let ptr = Rc::as_ptr(arr) as *const ();
+                if seen.contains(&ptr) {
+                    return write!(f, "[Circular]");
+                }
+                if seen.len() >= MAX_PRINT_DEPTH {
+                    return write!(f, "[...]");
+                }
+                seen.push(ptr);
+
+                write!(f, "[")?;
+                for (i, value) in arr.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    value.fmt_element(f, seen)?;
+                }
+
+                seen.pop();
+                write!(f, "]")
+            },
+            RuntimeVal::NativeFn(func) => write!(f, "[native fn {}]", func.name),
+            RuntimeVal::Function(func) => write!(f, "[fn {}]", func.name.as_deref().unwrap_or("<anonymous>")),
+            RuntimeVal::Closure(func) => write!(f, "[fn {}]", func.name().unwrap_or("<anonymous>")),
+            RuntimeVal::NullVal => write!(f, "null"),
+        }
     }
 }
 
-fn eval_binary_expr(left: RuntimeVal, right: RuntimeVal, op: &str) -> RuntimeVal {
+// how many arrays and objects deep a value is printed
+const MAX_PRINT_DEPTH: usize = 64;
 
-    let left: Option<f64> = match left {
-        RuntimeVal::NumberVal(n) => Some(n),
-        _ => None,
-    };
+impl RuntimeVal {
+    // quote strings inside objects and arrays so `["1"]` and `[1]` look different
+    fn fmt_element(&self, f: &mut std::fmt::Formatter<'_>, seen: &mut Vec<*const ()>) -> std::fmt::Result {
+        match self {
+            RuntimeVal::StringVal(s) => write!(f, "{:?}", s),
+            value => value.fmt_nested(f, seen),
+        }
+    }
+}
 
-    let right: Option<f64> = match right {
-        RuntimeVal::NumberVal(n) => Some(n),
-        _ => None,
-    };
+impl std::fmt::Display for RuntimeVal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_nested(f, &mut Vec::new())
+    }
+}
 
-    if left.is_some() && right.is_some() {
-        return eval_numeric_binary_expr(left.unwrap(), right.unwrap(), op);
+// Objects can contain themselves, so Debug goes through the cycle aware Display
+impl std::fmt::Debug for RuntimeVal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeVal::NumberVal(n) => write!(f, "NumberVal({:?})", n),
+            RuntimeVal::BoolVal(b) => write!(f, "BoolVal({:?})", b),
+            RuntimeVal::StringVal(s) => write!(f, "StringVal({:?})", s),
+            RuntimeVal::ObjectVal(_) => write!(f, "ObjectVal({})", self),
+            RuntimeVal::ArrayVal(_) => write!(f, "ArrayVal({})", self),
+            RuntimeVal::NativeFn(func) => write!(f, "{:?}", func),
+            RuntimeVal::Function(func) => write!(f, "{:?}", func),
+            RuntimeVal::Closure(func) => write!(f, "{:?}", func),
+            RuntimeVal::NullVal => write!(f, "NullVal"),
+        }
     }
+}
 
-    RuntimeVal::NullVal
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    UndefinedVariable(String, Span),
+    AlreadyDefined {
+        name: String,
+        span: Span,
+        previous: Option<Span>,
+    },
+    AssignToConstant {
+        name: String,
+        span: Span,
+        declared: Option<Span>,
+    },
+    InvalidAssignmentTarget(Span),
+    DivisionByZero(Span),
+    UnknownOperator(String, Span),
+    InvalidSyntax(Span),
+    NotCallable(String, Span),
+    InvalidArgument(String, Span),
+    ReturnOutsideFunction(Span),
+    MissingProperty(String, Span),
+    NotAnObject(String, Span),
+    InvalidPropertyKey(String, Span),
+    IndexOutOfBounds(f64, usize, Span),
+    InvalidOperands {
+        operator: String,
+        left: String,
+        right: String,
+        span: Span,
+    },
+    InvalidOperand {
+        operator: String,
+        operand: String,
+        span: Span,
+    },
+    BreakOutsideLoop(Span),
+    ContinueOutsideLoop(Span),
+    TypeMismatch {
+        expected: String,
+        found: String,
+        span: Span,
+    },
+    Interrupted(Span),
+    FuelExhausted(Span),
+    CallDepthExceeded(usize, Span),
+    AllocationLimitExceeded(usize, Span),
+    PermissionDenied {
+        function: String,
+        capability: Capability,
+        span: Span,
+    },
+    ModuleNotFound {
+        path: String,
+        span: Span,
+    },
+    ImportCycle {
+        // every module on the cycle, starting and ending with the same one
+        chain: Vec<String>,
+        span: Span,
+    },
+    MissingExport {
+        name: String,
+        module: String,
+        span: Span,
+    },
+    ImportDenied {
+        path: String,
+        reason: String,
+        span: Span,
+    },
 }
 
-fn eval_identifier(symbol: Identifier, env: &mut Environment) -> RuntimeVal {
-    let symbol = symbol.symbol;
-    let env = env.resolve(&symbol);
-    
-    match env {
-        Some(e) => {
-            let val = match e.get(&symbol) {
-                Some(v) => v,
-                None => RuntimeVal::NullVal,
+impl RuntimeError {
+    pub fn span(&self) -> Span {
+        match self {
+            RuntimeError::UndefinedVariable(_, span) => *span,
+            RuntimeError::AlreadyDefined { span, .. } => *span,
+            RuntimeError::AssignToConstant { span, .. } => *span,
+            RuntimeError::InvalidAssignmentTarget(span) => *span,
+            RuntimeError::DivisionByZero(span) => *span,
+            RuntimeError::UnknownOperator(_, span) => *span,
+            RuntimeError::InvalidSyntax(span) => *span,
+            RuntimeError::NotCallable(_, span) => *span,
+            RuntimeError::InvalidArgument(_, span) => *span,
+            RuntimeError::ReturnOutsideFunction(span) => *span,
+            RuntimeError::MissingProperty(_, span) => *span,
+            RuntimeError::NotAnObject(_, span) => *span,
+            RuntimeError::InvalidPropertyKey(_, span) => *span,
+            RuntimeError::IndexOutOfBounds(_, _, span) => *span,
+            RuntimeError::InvalidOperands { span, .. } => *span,
+            RuntimeError::InvalidOperand { span, .. } => *span,
+            RuntimeError::BreakOutsideLoop(span) => *span,
+            RuntimeError::ContinueOutsideLoop(span) => *span,
+            RuntimeError::TypeMismatch { span, .. } => *span,
+            RuntimeError::Interrupted(span) => *span,
+            RuntimeError::FuelExhausted(span) => *span,
+            RuntimeError::CallDepthExceeded(_, span) => *span,
+            RuntimeError::AllocationLimitExceeded(_, span) => *span,
+            RuntimeError::PermissionDenied { span, .. } => *span,
+            RuntimeError::ModuleNotFound { span, .. } => *span,
+            RuntimeError::ImportCycle { span, .. } => *span,
+            RuntimeError::MissingExport { span, .. } => *span,
+            RuntimeError::ImportDenied { span, .. } => *span,
+        }
+    }
+
+    fn span_mut(&mut self) -> &mut Span {
+        match self {
+            RuntimeError::UndefinedVariable(_, span) => span,
+            RuntimeError::AlreadyDefined { span, .. } => span,
+            RuntimeError::AssignToConstant { span, .. } => span,
+            RuntimeError::InvalidAssignmentTarget(span) => span,
+            RuntimeError::DivisionByZero(span) => span,
+            RuntimeError::UnknownOperator(_, span) => span,
+            RuntimeError::InvalidSyntax(span) => span,
+            RuntimeError::NotCallable(_, span) => span,
+            RuntimeError::InvalidArgument(_, span) => span,
+            RuntimeError::ReturnOutsideFunction(span) => span,
+            RuntimeError::MissingProperty(_, span) => span,
+            RuntimeError::NotAnObject(_, span) => span,
+            RuntimeError::InvalidPropertyKey(_, span) => span,
+            RuntimeError::IndexOutOfBounds(_, _, span) => span,
+            RuntimeError::InvalidOperands { span, .. } => span,
+            RuntimeError::InvalidOperand { span, .. } => span,
+            RuntimeError::BreakOutsideLoop(span) => span,
+            RuntimeError::ContinueOutsideLoop(span) => span,
+            RuntimeError::TypeMismatch { span, .. } => span,
+            RuntimeError::Interrupted(span) => span,
+            RuntimeError::FuelExhausted(span) => span,
+            RuntimeError::CallDepthExceeded(_, span) => span,
+            RuntimeError::AllocationLimitExceeded(_, span) => span,
+            RuntimeError::PermissionDenied { span, .. } => span,
+            RuntimeError::ModuleNotFound { span, .. } => span,
+            RuntimeError::ImportCycle { span, .. } => span,
+            RuntimeError::MissingExport { span, .. } => span,
+            RuntimeError::ImportDenied { span, .. } => span,
+        }
+    }
+
+    // Errors raised outside the tree walk (environment, native functions) don't
+    // know which node caused them, the interpreter attaches that afterwards.
+    pub(crate) fn at(mut self, at: Span) -> Self {
+        let span = self.span_mut();
+        if *span == Span::default() {
+            *span = at;
+        }
+        self
+    }
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeError::UndefinedVariable(name, _) => write!(f, "Variable {} not defined", name),
+            RuntimeError::AlreadyDefined { name, .. } => write!(f, "Variable {} already defined", name),
+            RuntimeError::AssignToConstant { name, .. } => write!(f, "Cannot assign to constant {}", name),
+            RuntimeError::InvalidAssignmentTarget(_) => write!(f, "Invalid assignment target"),
+            RuntimeError::DivisionByZero(_) => write!(f, "Division by zero"),
+            RuntimeError::UnknownOperator(op, _) => write!(f, "Unknown operator {}", op),
+            RuntimeError::InvalidSyntax(_) => write!(f, "Cannot evaluate a statement that failed to parse"),
+            RuntimeError::NotCallable(type_name, _) => write!(f, "Value of type {} is not callable", type_name),
+            RuntimeError::InvalidArgument(message, _) => write!(f, "{}", message),
+            RuntimeError::ReturnOutsideFunction(_) => write!(f, "Cannot return outside of a function"),
+            RuntimeError::MissingProperty(key, _) => write!(f, "Property {} does not exist", key),
+            RuntimeError::NotAnObject(type_name, _) => write!(f, "Cannot access properties of {}", type_name),
+            RuntimeError::InvalidPropertyKey(type_name, _) => write!(f, "Cannot use a value of type {} as a property key", type_name),
+            RuntimeError::IndexOutOfBounds(index, length, _) => write!(f, "Index {} out of bounds for length {}", index, length),
+            RuntimeError::InvalidOperands { operator, left, right, .. } => write!(f, "Cannot apply {} to {} and {}", operator, left, right),
+            RuntimeError::InvalidOperand { operator, operand, .. } => write!(f, "Cannot apply {} to {}", operator, operand),
+            RuntimeError::BreakOutsideLoop(_) => write!(f, "Cannot break outside of a loop"),
+            RuntimeError::ContinueOutsideLoop(_) => write!(f, "Cannot continue outside of a loop"),
+            RuntimeError::TypeMismatch { expected, found, .. } => write!(f, "Expected {}, got {}", expected, found),
+            RuntimeError::Interrupted(_) => write!(f, "Script was interrupted"),
+            RuntimeError::FuelExhausted(_) => write!(f, "Script ran out of fuel"),
+            RuntimeError::CallDepthExceeded(max, _) => write!(f, "Maximum call depth of {} exceeded", max),
+            RuntimeError::AllocationLimitExceeded(max, _) => write!(f, "Allocation limit of {} bytes exceeded", max),
+            RuntimeError::PermissionDenied { function, capability, .. } => write!(f, "Permission denied: {}() needs the {} capability", function, capability),
+            RuntimeError::ModuleNotFound { path, .. } => write!(f, "Cannot find module \"{}\"", path),
+            RuntimeError::ImportCycle { chain, .. } => write!(f, "Import cycle: {}", chain.join(" -> ")),
+            RuntimeError::MissingExport { name, module, .. } => write!(f, "Module \"{}\" does not export {}", module, name),
+            RuntimeError::ImportDenied { path, reason, .. } => write!(f, "Cannot import \"{}\": {}", path, reason),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+// Anything that stops the tree walk early: errors, `return` unwinding to the enclosing call,
+// and `break`/`continue` unwinding to the enclosing loop
+#[derive(Debug)]
+enum Unwind {
+    Error(RuntimeError),
+    Return(RuntimeVal, Span),
+    Break(Span),
+    Continue(Span),
+}
+
+impl From<RuntimeError> for Unwind {
+    fn from(e: RuntimeError) -> Self {
+        Unwind::Error(e)
+    }
+}
+
+type EvalResult = Result<RuntimeVal, Unwind>;
+
+fn eval_numeric_binary_expr(left: f64, right: f64, op: &str) -> Result<RuntimeVal, RuntimeError> {
+    match op {
+        "+" => Ok(RuntimeVal::NumberVal(left + right)),
+        "-" => Ok(RuntimeVal::NumberVal(left - right)),
+        "*" => Ok(RuntimeVal::NumberVal(left * right)),
+        "/" | "%" if right == 0.0 => Err(RuntimeError::DivisionByZero(Span::default())),
+        "/" => Ok(RuntimeVal::NumberVal(left / right)),
+        "%" => Ok(RuntimeVal::NumberVal(left % right)),
+        "<" => Ok(RuntimeVal::BoolVal(left < right)),
+        "<=" => Ok(RuntimeVal::BoolVal(left <= right)),
+        ">" => Ok(RuntimeVal::BoolVal(left > right)),
+        ">=" => Ok(RuntimeVal::BoolVal(left >= right)),
+        _ => Err(RuntimeError::UnknownOperator(op.to_string(), Span::default())),
+    }
+}
+
+pub(crate) fn eval_binary_expr(left: RuntimeVal, right: RuntimeVal, op: &str) -> Result<RuntimeVal, RuntimeError> {
+    match (left, right) {
+        (left, right) if op == "==" => Ok(RuntimeVal::BoolVal(left.equals(&right))),
+        (left, right) if op == "!=" => Ok(RuntimeVal::BoolVal(!left.equals(&right))),
+        (RuntimeVal::NumberVal(left), RuntimeVal::NumberVal(right)) => {
+            eval_numeric_binary_expr(left, right, op)
+        },
+        // a string on either side of + concatenates
+        (left @ RuntimeVal::StringVal(_), right) | (left, right @ RuntimeVal::StringVal(_)) if op == "+" => {
+            Ok(RuntimeVal::string(&format!("{}{}", left, right)))
+        },
+        (RuntimeVal::StringVal(left), RuntimeVal::StringVal(right)) if matches!(op, "<" | "<=" | ">" | ">=") => {
+            let result = match op {
+                "<" => left < right,
+                "<=" => left <= right,
+                ">" => left > right,
+                _ => left >= right,
             };
-            val.clone()
+            Ok(RuntimeVal::BoolVal(result))
         },
-        None => panic!("Variable {} not defined", symbol),
+        (left, right) => Err(RuntimeError::InvalidOperands {
+            operator: op.to_string(),
+            left: left.type_name().to_string(),
+            right: right.type_name().to_string(),
+            span: Span::default(),
+        }),
+    }
+}
+
+fn eval_unary_expr(unary: &Unary, env: &mut Environment) -> EvalResult {
+    let argument = eval_expr(&unary.argument, env)?;
+    Ok(eval_unary_op(&unary.operator, argument, unary.span)?)
+}
+
+pub(crate) fn eval_unary_op(op: &str, argument: RuntimeVal, span: Span) -> Result<RuntimeVal, RuntimeError> {
+    match (op, argument) {
+        ("!", value) => Ok(RuntimeVal::BoolVal(!value.is_truthy())),
+        ("-", RuntimeVal::NumberVal(n)) => Ok(RuntimeVal::NumberVal(-n)),
+        (op, value) => Err(RuntimeError::InvalidOperand {
+            operator: op.to_string(),
+            operand: value.type_name().to_string(),
+            span,
+        }),
     }
 }
 
-fn eval_object_expr(obj: ObjectLiteral, env: &mut Environment) -> RuntimeVal {
+// && and || only evaluate the right hand side when the left doesn't decide the result
+fn eval_logical_expr(b: &Binary, env: &mut Environment) -> EvalResult {
+    let left = eval_expr(&b.left, env)?.is_truthy();
+
+    let result = match b.operator.as_str() {
+        "&&" => left && eval_expr(&b.right, env)?.is_truthy(),
+        _ => left || eval_expr(&b.right, env)?.is_truthy(),
+    };
+
+    Ok(RuntimeVal::BoolVal(result))
+}
+
+fn eval_identifier(ident: &Identifier, env: &mut Environment) -> EvalResult {
+    match env.get(&ident.symbol) {
+        Some(val) => Ok(val),
+        None => Err(RuntimeError::UndefinedVariable(ident.symbol.clone(), ident.span).into()),
+    }
+}
+
+fn eval_object_expr(obj: &ObjectLiteral, env: &mut Environment) -> EvalResult {
     let mut map = HashMap::new();
-    for prop in obj.into_iter() {
-        let key = prop.key;
+    for prop in obj {
+        let key = prop.key.clone();
 
-        let val = match prop.value {
-            Some(e) => eval_expr(*e, env),
+        let val = match &prop.value {
+            Some(e) => eval_expr(e, env)?,
             None => {
                 match env.get(&key) {
-                    Some(v) => v.clone(),
-                    None => panic!("Property {} not defined", key),
+                    Some(v) => v,
+                    None => return Err(RuntimeError::UndefinedVariable(key, prop.span).into()),
                 }
             },
         };
 
         map.insert(key, val);
     }
-    RuntimeVal::ObjectVal(map)
+
+    let object = RuntimeVal::object(map);
+    env.budget().allocate_value(&object, obj.span)?;
+    Ok(object)
+}
+
+fn eval_array_expr(arr: &ArrayLiteral, env: &mut Environment) -> EvalResult {
+    let mut values = Vec::with_capacity(arr.elements.len());
+    for element in &arr.elements {
+        values.push(eval_expr(element, env)?);
+    }
+
+    let array = RuntimeVal::array(values);
+    env.budget().allocate_value(&array, arr.span)?;
+    Ok(array)
+}
+
+// the property a member expression refers to: `obj.key` or `obj[expr]`
+fn eval_member_property(member: &MemberExpr, env: &mut Environment) -> EvalResult {
+    if !member.computed {
+        return match member.property.as_ref() {
+            Expression::Identifier(ident) => Ok(RuntimeVal::string(&ident.symbol)),
+            other => Err(RuntimeError::InvalidPropertyKey("expression".to_string(), other.span()).into()),
+        };
+    }
+
+    eval_expr(&member.property, env)
+}
+
+// object keys are strings, numbers and booleans are converted like JS does
+fn property_key(property: &RuntimeVal, span: Span) -> Result<String, RuntimeError> {
+    match property {
+        RuntimeVal::StringVal(s) => Ok(s.to_string()),
+        RuntimeVal::NumberVal(n) => Ok(n.to_string()),
+        RuntimeVal::BoolVal(b) => Ok(b.to_string()),
+        other => Err(RuntimeError::InvalidPropertyKey(other.type_name().to_string(), span)),
+    }
+}
+
+// validates `property` as an index into something of `length` elements
+fn property_index(property: &RuntimeVal, length: usize, span: Span) -> Result<usize, RuntimeError> {
+    match property {
+        RuntimeVal::NumberVal(n) if n.fract() == 0.0 => {
+            if *n < 0.0 || *n >= length as f64 {
+                return Err(RuntimeError::IndexOutOfBounds(*n, length, span));
+            }
+            Ok(*n as usize)
+        },
+        other => Err(RuntimeError::InvalidPropertyKey(other.type_name().to_string(), span)),
+    }
+}
+
+// where the parts of a member expression are, for error reporting
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MemberSpans {
+    pub object: Span,
+    pub property: Span,
+    pub member: Span,
+}
+
+impl MemberSpans {
+    pub(crate) fn of(member: &MemberExpr) -> Self {
+        MemberSpans {
+            object: member.object.span(),
+            property: member.property.span(),
+            member: member.span,
+        }
+    }
+}
+
+fn eval_member_expr(member: &MemberExpr, env: &mut Environment) -> EvalResult {
+    let object = eval_expr(&member.object, env)?;
+    let property = eval_member_property(member, env)?;
+
+    Ok(get_property(object, property, member.computed, MemberSpans::of(member))?)
+}
+
+/// Reads `object.property`, or `object[property]` when `computed`.
+pub(crate) fn get_property(object: RuntimeVal, property: RuntimeVal, computed: bool, spans: MemberSpans) -> Result<RuntimeVal, RuntimeError> {
+    match object {
+        RuntimeVal::ObjectVal(obj) => {
+            let key = property_key(&property, spans.property)?;
+            match obj.borrow().get(&key) {
+                Some(value) => Ok(value.clone()),
+                None => Err(RuntimeError::MissingProperty(key, spans.property)),
+            }
+        },
+        RuntimeVal::StringVal(s) => match property {
+            RuntimeVal::StringVal(key) if !computed && &*key == "length" => {
+                Ok(RuntimeVal::NumberVal(s.chars().count() as f64))
+            },
+            RuntimeVal::StringVal(key) => Err(RuntimeError::MissingProperty(key.to_string(), spans.property)),
+            index => {
+                let index = property_index(&index, s.chars().count(), spans.property)?;
+                let c = s.chars().nth(index).unwrap_or_default();
+                Ok(RuntimeVal::string(&c.to_string()))
+            },
+        },
+        RuntimeVal::ArrayVal(arr) => match property {
+            RuntimeVal::StringVal(key) if !computed && &*key == "length" => {
+                Ok(RuntimeVal::NumberVal(arr.borrow().len() as f64))
+            },
+            RuntimeVal::StringVal(key) if !computed => match builtins::array_method(&arr, &key) {
+                Some(method) => Ok(RuntimeVal::NativeFn(method)),
+                None => Err(RuntimeError::MissingProperty(key.to_string(), spans.property)),
+            },
+            index => {
+                let arr = arr.borrow();
+                let index = property_index(&index, arr.len(), spans.property)?;
+                Ok(arr[index].clone())
+            },
+        },
+        other => Err(RuntimeError::NotAnObject(other.type_name().to_string(), spans.object)),
+    }
+}
+
+fn eval_member_assignment(member: &MemberExpr, value: RuntimeVal, env: &mut Environment) -> EvalResult {
+    let object = eval_expr(&member.object, env)?;
+    let property = eval_member_property(member, env)?;
+
+    Ok(set_property(object, property, value, MemberSpans::of(member), env.budget())?)
+}
+
+/// Writes `value` to `object[property]`, returning the value. New properties
+/// are charged to `budget`.
+pub(crate) fn set_property(object: RuntimeVal, property: RuntimeVal, value: RuntimeVal, spans: MemberSpans, budget: &Budget) -> Result<RuntimeVal, RuntimeError> {
+    match object {
+        RuntimeVal::ObjectVal(obj) => {
+            let key = property_key(&property, spans.property)?;
+            if !obj.borrow().contains_key(&key) {
+                budget.allocate(key.len() + budget::VALUE_SIZE, spans.member)?;
+            }
+            obj.borrow_mut().insert(key, value.clone());
+            Ok(value)
+        },
+        RuntimeVal::ArrayVal(arr) => {
+            let mut arr = arr.borrow_mut();
+            let index = property_index(&property, arr.len(), spans.property)?;
+            arr[index] = value.clone();
+            Ok(value)
+        },
+        // strings are immutable
+        RuntimeVal::StringVal(_) => Err(RuntimeError::InvalidAssignmentTarget(spans.member)),
+        other => Err(RuntimeError::NotAnObject(other.type_name().to_string(), spans.object)),
+    }
 }
 
-fn eval_expr(expr: Expression, env: &mut Environment) -> RuntimeVal {
+fn eval_call_expr(call: &CallExpr, env: &mut Environment) -> EvalResult {
+    let callee = eval_expr(&call.caller, env)?;
+
+    let mut args = Vec::new();
+    for arg in &call.arguments {
+        args.push(eval_expr(arg, env)?);
+    }
+
+    call_function(callee, args, env).map_err(|e| e.at(call.span).into())
+}
+
+/// Calls a function value, native or defined in a script, with already evaluated arguments.
+pub(crate) fn call_function(callee: RuntimeVal, args: Vec<RuntimeVal>, env: &mut Environment) -> Result<RuntimeVal, RuntimeError> {
+    match callee {
+        RuntimeVal::NativeFn(native) => (native.func)(args, env),
+        RuntimeVal::Function(func) => {
+            // every nested call recurses on the rust stack, so the depth is limited,
+            // counted where the function lives as the caller may be a host callback
+            let budget = func.env.budget();
+            budget.enter_call(Span::default())?;
+            let result = call_script_function(&func, args);
+            budget.exit_call();
+            result
+        },
+        RuntimeVal::Closure(closure) => vm::call(&closure, args),
+        other => Err(RuntimeError::NotCallable(other.type_name().to_string(), Span::default())),
+    }
+}
+
+fn call_script_function(func: &Function, args: Vec<RuntimeVal>) -> Result<RuntimeVal, RuntimeError> {
+    // run the body in a fresh scope on top of the one the function was defined in
+    let mut scope = Environment::new_with_parent(func.env.clone());
+
+    let mut args = args.into_iter();
+    for param in func.parameters.iter() {
+        let value = args.next().unwrap_or(RuntimeVal::NullVal);
+        scope.declare(&param.symbol, value, false, param.span)?;
+    }
+
+    match eval_body(&func.body, &mut scope) {
+        Ok(value) => Ok(value),
+        Err(Unwind::Return(value, _)) => Ok(value),
+        Err(Unwind::Error(e)) => Err(e),
+        Err(Unwind::Break(span)) => Err(RuntimeError::BreakOutsideLoop(span)),
+        Err(Unwind::Continue(span)) => Err(RuntimeError::ContinueOutsideLoop(span)),
+    }
+}
+
+fn eval_expr(expr: &Expression, env: &mut Environment) -> EvalResult {
+    env.budget().step(expr.span())?;
+
     match expr {
         Expression::Identifier(ident) => eval_identifier(ident, env),
         Expression::ObjectLiteral(obj) => eval_object_expr(obj, env),
-        Expression::NumericLiteral(n) => RuntimeVal::NumberVal(n.value),
+        Expression::ArrayLiteral(arr) => eval_array_expr(arr, env),
+        Expression::NumericLiteral(n) => Ok(RuntimeVal::NumberVal(n.value)),
+        Expression::StringLiteral(lit) => Ok(RuntimeVal::string(&lit.value)),
+        Expression::Binary(b) if b.operator == "&&" || b.operator == "||" => eval_logical_expr(b, env),
         Expression::Binary(b) => {
-            let left = eval_expr(*b.left, env);
-            let right = eval_expr(*b.right, env);
+            let left = eval_expr(&b.left, env)?;
+            let right = eval_expr(&b.right, env)?;
             let op = b.operator.as_str();
-            eval_binary_expr(left, right, op)
+            let result = eval_binary_expr(left, right, op).map_err(|e| match e {
+                RuntimeError::DivisionByZero(_) => e.at(b.right.span()),
+                _ => e.at(b.span),
+            })?;
+
+            // concatenating makes a new string
+            env.budget().allocate_value(&result, b.span)?;
+            Ok(result)
         },
+        Expression::Unary(u) => eval_unary_expr(u, env),
+        Expression::Call(call) => eval_call_expr(call, env),
+        Expression::Member(member) => eval_member_expr(member, env),
+        Expression::Function(f) => Ok(RuntimeVal::Function(Rc::new(Function {
+            name: None,
+            parameters: f.parameters.clone(),
+            body: f.body.clone(),
+            env: env.clone(),
+        }))),
         Expression::Assignment(a) => {
 
-            match *a.assignee {
+            match a.assignee.as_ref() {
                 Expression::Identifier(i) => {
-                    let symbol = i.symbol;
-                    let value = eval_expr(*a.value, env);
-                    env.assign(&symbol, value.clone());
-                    value
+                    let value = eval_expr(&a.value, env)?;
+                    env.assign(&i.symbol, value).map_err(|e| e.at(a.span).into())
+                },
+                Expression::Member(member) => {
+                    let value = eval_expr(&a.value, env)?;
+                    eval_member_assignment(member, value, env)
                 },
-                _ => panic!("Cannot assign to non-identifier (yet)"),
+                other => Err(RuntimeError::InvalidAssignmentTarget(other.span()).into()),
             }
         },
-        #[allow(unreachable_patterns)]
-        _ => {
-            println!("Expression: {:#?} not yet implemented", expr);
-            RuntimeVal::NullVal
-        },
+        // properties only appear inside object literals
+        Expression::Property(p) => Err(RuntimeError::InvalidSyntax(p.span).into()),
     }
 }
 
-fn eval_var_decleration(var: VariableDecleration, env: &mut Environment) -> RuntimeVal {
-    let value = match var.value {
-        Some(v) => eval_expr(v, env),
+fn eval_var_decleration(var: &VariableDecleration, env: &mut Environment) -> EvalResult {
+    let value = match &var.value {
+        Some(v) => eval_expr(v, env)?,
         None => RuntimeVal::NullVal,
     };
 
-    env.set(&var.identifier.symbol, value.clone(), var.constant);
+    Ok(env.declare(&var.identifier.symbol, value, var.constant, var.identifier.span)?)
+}
+
+fn eval_fn_declaration(decl: &FunctionDeclaration, env: &mut Environment) -> EvalResult {
+    // the function captures the scope it's declared in, which is also where its
+    // own name ends up, so it can call itself
+    let func = RuntimeVal::Function(Rc::new(Function {
+        name: Some(decl.name.symbol.clone()),
+        parameters: decl.parameters.clone(),
+        body: decl.body.clone(),
+        env: env.clone(),
+    }));
 
-    value
+    Ok(env.declare(&decl.name.symbol, func, false, decl.name.span)?)
 }
 
-fn eval_stmt(stmt: Statement, env: &mut Environment) -> RuntimeVal {
-    match stmt {
-        Statement::VariableDecleration(var) => {
-            return eval_var_decleration(var, env);
-        },
-        Statement::Program(p) => {
-            let mut last_val = RuntimeVal::NullVal;
-            for stmt in p.body {
-                last_val = evaluate(stmt, env);
+// evaluates statements in order, the value of the last one is the value of the body
+fn eval_body(body: &[StatementOrExpression], env: &mut Environment) -> EvalResult {
+    let mut last_val = RuntimeVal::NullVal;
+    for stmt in body {
+        last_val = eval_node(stmt, env)?;
+    }
+    Ok(last_val)
+}
+
+// a block gets its own scope, so declarations inside it are gone once it finishes
+fn eval_block(block: &BlockStatement, env: &mut Environment) -> EvalResult {
+    let mut scope = Environment::new_with_parent(env.clone());
+    eval_body(&block.body, &mut scope)
+}
+
+fn eval_if_stmt(stmt: &IfStatement, env: &mut Environment) -> EvalResult {
+    if eval_expr(&stmt.condition, env)?.is_truthy() {
+        return eval_block(&stmt.consequent, env);
+    }
+
+    match &stmt.alternate {
+        Some(alternate) => eval_stmt(alternate, env),
+        None => Ok(RuntimeVal::NullVal),
+    }
+}
+
+// runs one iteration of a loop body, returns false when the loop should stop
+fn eval_loop_body(body: &BlockStatement, env: &mut Environment) -> Result<bool, Unwind> {
+    match eval_block(body, env) {
+        Ok(_) | Err(Unwind::Continue(_)) => Ok(true),
+        Err(Unwind::Break(_)) => Ok(false),
+        Err(other) => Err(other),
+    }
+}
+
+fn eval_while_stmt(stmt: &WhileStatement, env: &mut Environment) -> EvalResult {
+    while eval_expr(&stmt.condition, env)?.is_truthy() {
+        if !eval_loop_body(&stmt.body, env)? {
+            break;
+        }
+    }
+    Ok(RuntimeVal::NullVal)
+}
+
+fn eval_for_stmt(stmt: &ForStatement, env: &mut Environment) -> EvalResult {
+    // variables declared in the initializer live for the whole loop, each
+    // iteration of the body gets a fresh scope below that
+    let mut scope = Environment::new_with_parent(env.clone());
+
+    if let Some(init) = &stmt.init {
+        eval_node(init, &mut scope)?;
+    }
+
+    loop {
+        if let Some(condition) = &stmt.condition {
+            if !eval_expr(condition, &mut scope)?.is_truthy() {
+                break;
             }
-            last_val
+        }
+
+        if !eval_loop_body(&stmt.body, &mut scope)? {
+            break;
+        }
+
+        if let Some(update) = &stmt.update {
+            eval_expr(update, &mut scope)?;
+        }
+    }
+    Ok(RuntimeVal::NullVal)
+}
+
+fn eval_stmt(stmt: &Statement, env: &mut Environment) -> EvalResult {
+    env.budget().step(stmt.span())?;
+
+    match stmt {
+        Statement::VariableDecleration(var) => eval_var_decleration(var, env),
+        Statement::FunctionDeclaration(decl) => eval_fn_declaration(decl, env),
+        Statement::Return(ret) => {
+            let value = match &ret.value {
+                Some(v) => eval_expr(v, env)?,
+                None => RuntimeVal::NullVal,
+            };
+            Err(Unwind::Return(value, ret.span))
         },
+        Statement::Block(block) => eval_block(block, env),
+        Statement::If(stmt) => eval_if_stmt(stmt, env),
+        Statement::While(stmt) => eval_while_stmt(stmt, env),
+        Statement::For(stmt) => eval_for_stmt(stmt, env),
+        Statement::Break(span) => Err(Unwind::Break(*span)),
+        Statement::Continue(span) => Err(Unwind::Continue(*span)),
+        // the module loader bound the imported names before the program started
+        Statement::Import(_) => Ok(RuntimeVal::NullVal),
+        Statement::Export(export) => eval_stmt(&export.declaration, env),
+        Statement::Error(span) => Err(RuntimeError::InvalidSyntax(*span).into()),
+        Statement::Program(p) => eval_body(&p.body, env),
     }
 }
 
-pub fn evaluate(ast_node: StatementOrExpression, env: &mut Environment) -> RuntimeVal {
+fn eval_node(ast_node: &StatementOrExpression, env: &mut Environment) -> EvalResult {
     match ast_node {
         StatementOrExpression::Expression(expr) => eval_expr(expr, env),
         StatementOrExpression::Statement(stmt) => eval_stmt(stmt, env),
     }
-}
\ No newline at end of file
+}
+
+pub fn evaluate(ast_node: StatementOrExpression, env: &mut Environment) -> Result<RuntimeVal, RuntimeError> {
+    match eval_node(&ast_node, env) {
+        Ok(value) => Ok(value),
+        Err(Unwind::Error(e)) => Err(e),
+        Err(Unwind::Return(_, span)) => Err(RuntimeError::ReturnOutsideFunction(span)),
+        Err(Unwind::Break(span)) => Err(RuntimeError::BreakOutsideLoop(span)),
+        Err(Unwind::Continue(span)) => Err(RuntimeError::ContinueOutsideLoop(span)),
+    }
+}
@@ -1,18 +1,32 @@
+use unicode_ident::{is_xid_continue, is_xid_start};
+
+use crate::span::Span;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum TokenType {
     // Literal Types
     Number,
     Identifier,
-    // String,
-    
+    String,
+
     // Keywords
     Let,
     Const,
+    Fn,
+    Return,
+    If,
+    Else,
+    While,
+    For,
+    Break,
+    Continue,
+    Import,
 
     
     // Grouping * Operators
     BinaryOperator,
     Equals,
+    Bang, // !
     OpenParen,
     CloseParen,
     
@@ -29,6 +43,9 @@ pub enum TokenType {
     OpenBracket,  // [
     CloseBracket, // ]
 
+    // Trivia, kept for tools but skipped by the parser
+    DocComment, // /// ...
+
     // End of File
     EOF,
 }
@@ -36,57 +53,425 @@ pub enum TokenType {
 static KEYWORDS: &[(&str, TokenType)] = &[
     ("let", TokenType::Let),
     ("const", TokenType::Const),
+    ("fn", TokenType::Fn),
+    ("return", TokenType::Return),
+    ("if", TokenType::If),
+    ("else", TokenType::Else),
+    ("while", TokenType::While),
+    ("for", TokenType::For),
+    ("break", TokenType::Break),
+    ("continue", TokenType::Continue),
+    ("import", TokenType::Import),
 ];
 
-#[derive(Debug)]
+impl TokenType {
+    pub fn is_keyword(self) -> bool {
+        KEYWORDS.iter().any(|(_, token_type)| *token_type == self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexError {
+    UnexpectedCharacter(char, Span),
+    UnterminatedString(Span),
+    InvalidEscape(String, Span),
+    InvalidNumber(String, Span),
+    UnterminatedComment(Span),
+}
+
+impl LexError {
+    pub fn span(&self) -> Span {
+        match self {
+            LexError::UnexpectedCharacter(_, span) => *span,
+            LexError::UnterminatedString(span) => *span,
+            LexError::InvalidEscape(_, span) => *span,
+            LexError::InvalidNumber(_, span) => *span,
+            LexError::UnterminatedComment(span) => *span,
+        }
+    }
+}
+
+impl std::fmt::Display for LexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LexError::UnexpectedCharacter(c, _) => write!(f, "Unhandled character \"{}\"", c),
+            LexError::UnterminatedString(_) => write!(f, "Unterminated string literal"),
+            LexError::InvalidEscape(escape, _) => write!(f, "Invalid escape sequence \"{}\"", escape),
+            LexError::InvalidNumber(literal, _) => write!(f, "Invalid number literal \"{}\"", literal),
+            LexError::UnterminatedComment(_) => write!(f, "Unterminated block comment"),
+        }
+    }
+}
+
+impl std::error::Error for LexError {}
+
+#[derive(Debug, Clone)]
 pub struct Token {
     pub value: String,
     pub t: TokenType,
+    pub span: Span,
 }
 
 impl Token {
-    pub fn new(value: String, t: TokenType) -> Token {
-        Token { value, t }
+    pub fn new(value: String, t: TokenType, span: Span) -> Token {
+        Token { value, t, span }
+    }
+}
+
+// Walks the source one char at a time, keeping track of where we are
+struct Cursor<'a> {
+    source: &'a str,
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(source: &'a str) -> Self {
+        Cursor { source, offset: 0, line: 1, column: 1 }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.source[self.offset..].chars().next()
+    }
+
+    // the char after the next one
+    fn peek_second(&self) -> Option<char> {
+        self.source[self.offset..].chars().nth(1)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    // zero width span at the current position
+    fn mark(&self) -> Span {
+        Span::new(self.offset, self.offset, self.line, self.column)
+    }
+
+    fn span_from(&self, mark: Span) -> Span {
+        Span { end: self.offset, ..mark }
+    }
+
+    fn slice(&self, span: Span) -> &'a str {
+        &self.source[span.start..span.end]
+    }
+}
+
+// Reads a string literal up to the closing `quote`, decoding escape sequences.
+// The token's value is the decoded contents.
+fn lex_string(cursor: &mut Cursor, quote: char, start: Span, errors: &mut Vec<LexError>) -> Token {
+    let mut value = String::new();
+
+    loop {
+        let escape_start = cursor.mark();
+        match cursor.peek() {
+            None | Some('\n') => {
+                errors.push(LexError::UnterminatedString(cursor.span_from(start)));
+                break;
+            }
+            Some(c) if c == quote => {
+                cursor.bump();
+                break;
+            }
+            Some('\\') => {
+                cursor.bump();
+                match cursor.bump() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('r') => value.push('\r'),
+                    Some('0') => value.push('\0'),
+                    Some('\\') => value.push('\\'),
+                    Some('"') => value.push('"'),
+                    Some('\'') => value.push('\''),
+                    Some('u') => match lex_unicode_escape(cursor) {
+                        Some(c) => value.push(c),
+                        None => {
+                            let span = cursor.span_from(escape_start);
+                            errors.push(LexError::InvalidEscape(cursor.slice(span).to_string(), span));
+                        }
+                    },
+                    _ => {
+                        let span = cursor.span_from(escape_start);
+                        errors.push(LexError::InvalidEscape(cursor.slice(span).to_string(), span));
+                    }
+                }
+            }
+            Some(c) => {
+                cursor.bump();
+                value.push(c);
+            }
+        }
+    }
+
+    Token::new(value, TokenType::String, cursor.span_from(start))
+}
+
+// the `{1F600}` part of a `\u{1F600}` escape
+fn lex_unicode_escape(cursor: &mut Cursor) -> Option<char> {
+    if cursor.peek() != Some('{') {
+        return None;
+    }
+    cursor.bump();
+
+    // take everything up to the closing brace so a bad escape is reported as a whole
+    let mut digits = String::new();
+    while let Some(c) = cursor.peek() {
+        if c == '}' || c == '"' || c == '\'' || c == '\n' {
+            break;
+        }
+        digits.push(c);
+        cursor.bump();
+    }
+
+    if cursor.peek() != Some('}') {
+        return None;
+    }
+    cursor.bump();
+
+    if digits.is_empty() || digits.len() > 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32)
+}
+
+// Skips a `/* ... */` comment whose opening `/*` has already been consumed.
+// Block comments nest, so code containing comments can be commented out.
+fn skip_block_comment(cursor: &mut Cursor, start: Span, errors: &mut Vec<LexError>) {
+    let mut depth = 1;
+
+    while depth > 0 {
+        match (cursor.bump(), cursor.peek()) {
+            (Some('/'), Some('*')) => {
+                cursor.bump();
+                depth += 1;
+            }
+            (Some('*'), Some('/')) => {
+                cursor.bump();
+                depth -= 1;
+            }
+            (Some(_), _) => {}
+            (None, _) => {
+                // only point at the opening `/*`, the span would cover the rest of the file
+                errors.push(LexError::UnterminatedComment(Span { end: start.start + 2, ..start }));
+                return;
+            }
+        }
+    }
+}
+
+// Reads a number literal whose first char, `first`, has already been consumed:
+// 42, 3.14, .5, 1e-9, 0xFF, 0b1010, 0o17 and 1_000_000.
+// The token's value is the number it evaluates to.
+fn lex_number(cursor: &mut Cursor, first: char, start: Span, errors: &mut Vec<LexError>) -> Token {
+    let radix = match (first, cursor.peek()) {
+        ('0', Some('x' | 'X')) => 16,
+        ('0', Some('o' | 'O')) => 8,
+        ('0', Some('b' | 'B')) => 2,
+        _ => 10,
+    };
+    if radix != 10 {
+        cursor.bump();
+    }
+
+    // take trailing letters too, so `12abc` is reported as one bad literal
+    let mut seen_dot = first == '.';
+    let mut seen_exponent = false;
+    loop {
+        match cursor.peek() {
+            Some('e' | 'E') if radix == 10 && !seen_exponent => {
+                seen_exponent = true;
+                cursor.bump();
+                if let Some('+' | '-') = cursor.peek() {
+                    cursor.bump();
+                }
+            }
+            Some(c) if c.is_ascii_alphanumeric() || c == '_' => {
+                cursor.bump();
+            }
+            // `1.5` continues the number, `1.foo` is member access
+            Some('.') if radix == 10 && !seen_dot && !seen_exponent && matches!(cursor.peek_second(), Some('0'..='9')) => {
+                seen_dot = true;
+                cursor.bump();
+            }
+            _ => break,
+        }
     }
+
+    let span = cursor.span_from(start);
+    let literal = cursor.slice(span);
+
+    let value = match parse_number(literal) {
+        Some(value) => value,
+        None => {
+            errors.push(LexError::InvalidNumber(literal.to_string(), span));
+            f64::NAN
+        }
+    };
+
+    Token::new(value.to_string(), TokenType::Number, span)
 }
 
-pub fn tokenize(source_code: &str) -> Vec<Token> {
+fn parse_number(literal: &str) -> Option<f64> {
+    let (digits, radix) = match literal.get(..2) {
+        Some("0x" | "0X") => (&literal[2..], 16),
+        Some("0o" | "0O") => (&literal[2..], 8),
+        Some("0b" | "0B") => (&literal[2..], 2),
+        _ => (literal, 10),
+    };
+
+    // separators are only allowed between two digits
+    let chars: Vec<char> = digits.chars().collect();
+    for (i, c) in chars.iter().enumerate() {
+        if *c == '_' {
+            let before = i.checked_sub(1).and_then(|i| chars.get(i));
+            let after = chars.get(i + 1);
+            if !before.is_some_and(|c| c.is_digit(radix)) || !after.is_some_and(|c| c.is_digit(radix)) {
+                return None;
+            }
+        }
+    }
+    let digits: String = chars.into_iter().filter(|c| *c != '_').collect();
+
+    if radix == 10 {
+        // f64's own parser also accepts words like "inf"
+        if !digits.chars().all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-')) {
+            return None;
+        }
+        return digits.parse().ok();
+    }
+
+    if digits.is_empty() {
+        return None;
+    }
+    digits
+        .chars()
+        .try_fold(0.0, |value, c| c.to_digit(radix).map(|d| value * radix as f64 + d as f64))
+}
+
+// identifiers follow Unicode's XID rules, with `_` and `$` allowed anywhere
+fn is_identifier_start(c: char) -> bool {
+    c == '_' || c == '$' || is_xid_start(c)
+}
+
+fn is_identifier_continue(c: char) -> bool {
+    c == '$' || is_xid_continue(c)
+}
+
+// whether `token` can end an expression, in which case a `.` after it is member access
+fn ends_expression(token: Option<&Token>) -> bool {
+    matches!(
+        token.map(|t| t.t),
+        Some(TokenType::Number | TokenType::Identifier | TokenType::String | TokenType::CloseParen | TokenType::CloseBracket)
+    )
+}
+
+pub fn tokenize(source_code: &str) -> Result<Vec<Token>, LexError> {
+    let (tokens, mut errors) = tokenize_recovering(source_code);
+    if !errors.is_empty() {
+        return Err(errors.remove(0));
+    }
+    Ok(tokens)
+}
+
+// Like `tokenize`, but skips over anything it can't make sense of so that
+// every bad character in the file gets reported.
+pub fn tokenize_recovering(source_code: &str) -> (Vec<Token>, Vec<LexError>) {
     let mut tokens = Vec::new();
-    let mut chars = source_code.chars().peekable();
+    let mut errors = Vec::new();
+    let mut cursor = Cursor::new(source_code);
 
     // Build each token util end of file
-    while let Some(c) = chars.next() {
+    loop {
+        let start = cursor.mark();
+        let c = match cursor.bump() {
+            Some(c) => c,
+            None => break,
+        };
+
+        let single = |t: TokenType| Token::new(c.to_string(), t, cursor.span_from(start));
+
         match c {
-            '(' => tokens.push(Token::new("(".to_string(), TokenType::OpenParen)),
-            ')' => tokens.push(Token::new(")".to_string(), TokenType::CloseParen)),
-            '{' => tokens.push(Token::new("{".to_string(), TokenType::OpenBrace)),
-            '}' => tokens.push(Token::new("}".to_string(), TokenType::CloseBrace)),
-            '[' => tokens.push(Token::new("[".to_string(), TokenType::OpenBracket)),
-            ']' => tokens.push(Token::new("]".to_string(), TokenType::CloseBracket)),
-            '+' => tokens.push(Token::new("+".to_string(), TokenType::BinaryOperator)),
-            '-' => tokens.push(Token::new("-".to_string(), TokenType::BinaryOperator)),
-            '*' => tokens.push(Token::new("*".to_string(), TokenType::BinaryOperator)),
-            '/' => tokens.push(Token::new("/".to_string(), TokenType::BinaryOperator)),
-            '%' => tokens.push(Token::new("%".to_string(), TokenType::BinaryOperator)),
-            '=' => tokens.push(Token::new("=".to_string(), TokenType::Equals)),
-            ';' => tokens.push(Token::new(";".to_string(), TokenType::Semicolon)),
-            ':' => tokens.push(Token::new(":".to_string(), TokenType::Colon)),
-            ',' => tokens.push(Token::new(",".to_string(), TokenType::Comma)),
-            '.' => tokens.push(Token::new(".".to_string(), TokenType::Dot)),
-            '0'..='9' => {
-                let mut number = String::new();
-                number.push(c);
-                while let Some(&('0'..='9')) = chars.peek() {
-                    number.push(chars.next().unwrap());
+            '(' => tokens.push(single(TokenType::OpenParen)),
+            ')' => tokens.push(single(TokenType::CloseParen)),
+            '{' => tokens.push(single(TokenType::OpenBrace)),
+            '}' => tokens.push(single(TokenType::CloseBrace)),
+            '[' => tokens.push(single(TokenType::OpenBracket)),
+            ']' => tokens.push(single(TokenType::CloseBracket)),
+            '/' if cursor.peek() == Some('/') => {
+                cursor.bump();
+
+                // `///` is a doc comment, but a line of slashes is just a comment
+                let is_doc = cursor.peek() == Some('/') && cursor.peek_second() != Some('/');
+                if is_doc {
+                    cursor.bump();
                 }
-                tokens.push(Token::new(number, TokenType::Number));
+
+                let text_start = cursor.mark();
+                while cursor.peek().is_some_and(|c| c != '\n') {
+                    cursor.bump();
+                }
+
+                if is_doc {
+                    let text = cursor.slice(cursor.span_from(text_start)).to_string();
+                    tokens.push(Token::new(text, TokenType::DocComment, cursor.span_from(start)));
+                }
+            },
+            '/' if cursor.peek() == Some('*') => {
+                cursor.bump();
+                skip_block_comment(&mut cursor, start, &mut errors);
+            },
+            '+' | '-' | '*' | '/' | '%' => tokens.push(single(TokenType::BinaryOperator)),
+            '=' | '!' | '<' | '>' => {
+                // ==, !=, <=, >=
+                if cursor.peek() == Some('=') {
+                    cursor.bump();
+                    let span = cursor.span_from(start);
+                    tokens.push(Token::new(cursor.slice(span).to_string(), TokenType::BinaryOperator, span));
+                    continue;
+                }
+
+                match c {
+                    '=' => tokens.push(single(TokenType::Equals)),
+                    '!' => tokens.push(single(TokenType::Bang)),
+                    _ => tokens.push(single(TokenType::BinaryOperator)),
+                }
+            },
+            '&' | '|' => {
+                // only the doubled && and || exist
+                if cursor.peek() == Some(c) {
+                    cursor.bump();
+                    let span = cursor.span_from(start);
+                    tokens.push(Token::new(cursor.slice(span).to_string(), TokenType::BinaryOperator, span));
+                } else {
+                    errors.push(LexError::UnexpectedCharacter(c, cursor.span_from(start)));
+                }
+            },
+            ';' => tokens.push(single(TokenType::Semicolon)),
+            ':' => tokens.push(single(TokenType::Colon)),
+            ',' => tokens.push(single(TokenType::Comma)),
+            // `.5` is a number, unless it follows something it could be a property of
+            '.' if matches!(cursor.peek(), Some('0'..='9')) && !ends_expression(tokens.last()) => {
+                tokens.push(lex_number(&mut cursor, c, start, &mut errors));
             }
-            'A'..='z' => {
-                let mut identifier = String::new();
-                identifier.push(c);
-                while let Some(&('A'..='z')) = chars.peek() {
-                    identifier.push(chars.next().unwrap());
+            '.' => tokens.push(single(TokenType::Dot)),
+            '"' | '\'' => tokens.push(lex_string(&mut cursor, c, start, &mut errors)),
+            '0'..='9' => tokens.push(lex_number(&mut cursor, c, start, &mut errors)),
+            c if is_identifier_start(c) => {
+                while cursor.peek().is_some_and(is_identifier_continue) {
+                    cursor.bump();
                 }
+                let span = cursor.span_from(start);
+                let identifier = cursor.slice(span).to_string();
 
                 // check for reserved keywords
                 let token_type = KEYWORDS
@@ -95,19 +480,19 @@ pub fn tokenize(source_code: &str) -> Vec<Token> {
                     .map(|(_, token_type)| *token_type)
                     .unwrap_or(TokenType::Identifier);
 
-                tokens.push(Token::new(identifier, token_type));
+                tokens.push(Token::new(identifier, token_type, span));
             },
             _ => {
                 if c.is_whitespace() {
                     continue;
                 }
 
-                panic!("Unhandled character \"{}\"", c);
+                errors.push(LexError::UnexpectedCharacter(c, cursor.span_from(start)));
             },
         }
     }
 
-    tokens.push(Token::new("EndOfFile".to_string(), TokenType::EOF));
+    tokens.push(Token::new("EndOfFile".to_string(), TokenType::EOF, cursor.mark()));
 
-    return tokens;
-}
\ No newline at end of file
+    (tokens, errors)
+}
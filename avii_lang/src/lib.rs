@@ -2,4 +2,17 @@ pub mod lexer;
 pub mod ast;
 pub mod parser;
 pub mod interpreter;
-pub mod environment;
\ No newline at end of file
+pub mod environment;
+pub mod span;
+pub mod diagnostics;
+pub mod builtins;
+pub mod compiler;
+pub mod vm;
+pub mod resolver;
+pub mod engine;
+pub mod convert;
+pub mod budget;
+pub mod capabilities;
+pub mod module;
+
+pub use engine::Engine;
@@ -0,0 +1,292 @@
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    ast::{ImportStatement, Program, Statement, StatementOrExpression},
+    interpreter::{RuntimeError, RuntimeVal},
+    span::Span,
+};
+
+/// A module that ran to the end, with the values its exports had at that point.
+/// Importers get copies of those values, not the module's variables.
+#[derive(Debug)]
+pub struct Module {
+    path: PathBuf,
+    exports: HashMap<String, RuntimeVal>,
+}
+
+impl Module {
+    pub(crate) fn new(path: PathBuf, exports: HashMap<String, RuntimeVal>) -> Self {
+        Module { path, exports }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RuntimeVal> {
+        self.exports.get(name)
+    }
+}
+
+/// The modules an engine has loaded. Each module runs once, every later import
+/// of it shares the values it exported the first time.
+#[derive(Debug, Default)]
+pub(crate) struct ModuleLoader {
+    // by canonical path
+    modules: HashMap<PathBuf, Rc<Module>>,
+    // the modules running right now, each imported by the one before it
+    loading: Vec<PathBuf>,
+}
+
+impl ModuleLoader {
+    pub(crate) fn get(&self, path: &Path) -> Option<Rc<Module>> {
+        self.modules.get(path).cloned()
+    }
+
+    // Marks `path` as running until `end` is called. A module that is already
+    // running further up imports itself through the ones in between.
+    pub(crate) fn begin(&mut self, path: PathBuf, span: Span) -> Result<(), RuntimeError> {
+        if let Some(start) = self.loading.iter().position(|p| *p == path) {
+            let chain = self.loading[start..]
+                .iter()
+                .chain(std::iter::once(&path))
+                .map(|p| display_name(p))
+                .collect();
+            return Err(RuntimeError::ImportCycle { chain, span });
+        }
+
+        self.loading.push(path);
+        Ok(())
+    }
+
+    pub(crate) fn end(&mut self) {
+        self.loading.pop();
+    }
+
+    pub(crate) fn insert(&mut self, module: Module) -> Rc<Module> {
+        let module = Rc::new(module);
+        self.modules.insert(module.path.clone(), module.clone());
+        module
+    }
+}
+
+/// The file `import` refers to, relative to the directory of the importing
+/// file. Only `.avii` files inside `root` can be imported.
+pub(crate) fn resolve_path(dir: &Path, root: &Path, import: &ImportStatement) -> Result<PathBuf, RuntimeError> {
+    let denied = |reason: String| RuntimeError::ImportDenied {
+        path: import.path.clone(),
+        reason,
+        span: import.path_span,
+    };
+    let check = |path: &Path| {
+        if path.extension().is_none_or(|ext| ext != "avii") {
+            return Err(denied("only .avii files can be imported".to_string()));
+        }
+        if !path.starts_with(root) {
+            return Err(denied(format!("it is outside the module root {}", root.display())));
+        }
+        Ok(())
+    };
+
+    // checked before touching the file system, so nothing is learned about
+    // files outside the root
+    check(&normalize(&dir.join(&import.path)))?;
+
+    let path = dir
+        .join(&import.path)
+        .canonicalize()
+        .ok()
+        .filter(|path| path.is_file())
+        .ok_or_else(|| RuntimeError::ModuleNotFound {
+            path: import.path.clone(),
+            span: import.path_span,
+        })?;
+
+    // and again once symlinks are followed
+    check(&path)?;
+    Ok(path)
+}
+
+// resolves `.` and `..` without looking at the file system
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => {
+                normalized.pop();
+            },
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+pub(crate) fn imports(program: &Program) -> impl Iterator<Item = &ImportStatement> {
+    program.body.iter().filter_map(|node| match node {
+        StatementOrExpression::Statement(Statement::Import(import)) => Some(import),
+        _ => None,
+    })
+}
+
+pub(crate) fn exported_names(program: &Program) -> Vec<String> {
+    program
+        .body
+        .iter()
+        .filter_map(|node| match node {
+            StatementOrExpression::Statement(Statement::Export(export)) => Some(export.name().symbol.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+// canonical paths are long, the file name is enough to follow a cycle
+fn display_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        capabilities::{Capabilities, Capability},
+        engine::EngineError,
+        Engine,
+    };
+
+    // A fresh directory with `files` in it, given as (relative path, source).
+    fn project(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("avii-modules-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        for (path, source) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        dir
+    }
+
+    fn engines() -> [Engine; 2] {
+        let capabilities = Capabilities::none().with(Capability::FsRead);
+        [Engine::with_capabilities(capabilities), Engine::with_capabilities(capabilities).with_vm()]
+    }
+
+    // the error in the module that failed, however deep in the imports it was
+    fn innermost(error: EngineError) -> EngineError {
+        match error {
+            EngineError::Module { error, .. } => innermost(*error),
+            other => other,
+        }
+    }
+
+    fn import_error(engine: &mut Engine, path: PathBuf) -> RuntimeError {
+        match engine.run_file(path).map_err(innermost) {
+            Err(EngineError::Runtime(e)) => e,
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn imports_run_each_module_once() {
+        let dir = project("once", &[
+            ("main.avii", r#"import { count } from "./counter.avii"; import { twice } from "./lib/twice.avii"; [count, twice]"#),
+            ("counter.avii", "let runs = 0; runs = runs + 1; export const count = runs;"),
+            ("lib/twice.avii", r#"import { count } from "../counter.avii"; export const twice = count * 2;"#),
+        ]);
+
+        for mut engine in engines() {
+            assert_eq!(engine.run_file(dir.join("main.avii")).unwrap().to_string(), "[1, 2]");
+        }
+    }
+
+    #[test]
+    fn exports_are_copied_when_the_module_loads() {
+        let dir = project("copies", &[
+            ("main.avii", r#"import { counter, inc } from "./counter.avii"; inc(); inc(); counter"#),
+            ("counter.avii", "export let counter = 0; export fn inc() { counter = counter + 1; }"),
+        ]);
+
+        for mut engine in engines() {
+            assert_eq!(engine.run_file(dir.join("main.avii")).unwrap().to_string(), "0");
+        }
+    }
+
+    #[test]
+    fn import_cycles() {
+        let dir = project("cycle", &[
+            ("a.avii", r#"import { b } from "./b.avii"; export const a = 1;"#),
+            ("b.avii", r#"import { a } from "./a.avii"; export const b = 2;"#),
+        ]);
+
+        for mut engine in engines() {
+            let error = import_error(&mut engine, dir.join("a.avii"));
+            assert_eq!(error.to_string(), "Import cycle: a.avii -> b.avii -> a.avii");
+        }
+    }
+
+    #[test]
+    fn missing_modules_and_exports() {
+        let dir = project("missing", &[
+            ("main.avii", r#"import { nope } from "./lib.avii";"#),
+            ("gone.avii", r#"import { x } from "./nowhere.avii";"#),
+            ("lib.avii", "export const yes = 1; const nope = 2;"),
+        ]);
+
+        for mut engine in engines() {
+            let error = import_error(&mut engine, dir.join("main.avii"));
+            assert_eq!(error.to_string(), r#"Module "./lib.avii" does not export nope"#);
+
+            let error = import_error(&mut engine, dir.join("gone.avii"));
+            assert_eq!(error.to_string(), r#"Cannot find module "./nowhere.avii""#);
+        }
+    }
+
+    #[test]
+    fn imports_stay_inside_the_module_root() {
+        let dir = project("root", &[
+            ("app/main.avii", r#"import { x } from "../lib.avii"; x"#),
+            ("app/data.avii", r#"import { x } from "./notes.txt"; x"#),
+            ("app/notes.txt", "export const x = 1;"),
+            ("lib.avii", "export const x = 1;"),
+        ]);
+
+        for mut engine in engines() {
+            let error = import_error(&mut engine, dir.join("app/main.avii"));
+            assert!(matches!(error, RuntimeError::ImportDenied { .. }), "{:?}", error);
+            assert!(error.to_string().contains("outside the module root"), "{}", error);
+
+            let error = import_error(&mut engine, dir.join("app/data.avii"));
+            assert_eq!(error.to_string(), r#"Cannot import "./notes.txt": only .avii files can be imported"#);
+        }
+
+        // a root above both lets the script reach the library
+        for engine in engines() {
+            let mut engine = engine.with_module_root(&dir);
+            assert_eq!(engine.run_file(dir.join("app/main.avii")).unwrap().to_string(), "1");
+        }
+    }
+
+    #[test]
+    fn imports_need_the_fs_read_capability() {
+        let dir = project("denied", &[
+            ("main.avii", r#"import { x } from "./lib.avii"; x"#),
+            ("lib.avii", "export const x = 1;"),
+        ]);
+
+        let mut engine = Engine::new();
+        let error = import_error(&mut engine, dir.join("main.avii"));
+        assert_eq!(
+            error.to_string(),
+            r#"Cannot import "./lib.avii": loading modules needs the fs_read capability"#
+        );
+    }
+}
@@ -3,27 +3,257 @@ use crate::ast::{
     Binary,
     NumericLiteral,
     Identifier, StatementOrExpression, Expression, Statement, VariableDecleration, Assignment, ObjectLiteral, Property, MemberExpr, CallExpr,
+    FunctionDeclaration, FunctionExpr, ReturnStatement, StringLiteral, Unary,
+    BlockStatement, IfStatement, WhileStatement, ForStatement, ArrayLiteral, ImportStatement, ExportDeclaration,
 };
 
-use crate::lexer::{tokenize, Token, TokenType};
+use crate::budget::Limits;
+use crate::lexer::{tokenize, tokenize_recovering, LexError, Token, TokenType};
+use crate::span::Span;
+
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Lex(LexError),
+    UnexpectedToken {
+        expected: TokenType,
+        found: TokenType,
+        value: String,
+        span: Span,
+    },
+    ExpectedExpression {
+        found: TokenType,
+        value: String,
+        span: Span,
+    },
+    ConstWithoutValue(String, Span),
+    InvalidNumber(String, Span),
+    TooDeeplyNested(usize, Span),
+    SourceTooLong {
+        length: usize,
+        max: usize,
+    },
+    // `import` or `export` inside a block or function
+    NotAtTopLevel(String, Span),
+    // a word that's only a keyword in one place, like `from` in an import
+    ExpectedKeyword {
+        keyword: String,
+        found: TokenType,
+        value: String,
+        span: Span,
+    },
+    // `export` in front of something that isn't a declaration
+    ExpectedDeclaration {
+        found: TokenType,
+        value: String,
+        span: Span,
+    },
+}
+
+impl ParseError {
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::Lex(e) => e.span(),
+            ParseError::UnexpectedToken { span, .. } => *span,
+            ParseError::ExpectedExpression { span, .. } => *span,
+            ParseError::ConstWithoutValue(_, span) => *span,
+            ParseError::InvalidNumber(_, span) => *span,
+            ParseError::TooDeeplyNested(_, span) => *span,
+            ParseError::SourceTooLong { .. } => Span::default(),
+            ParseError::NotAtTopLevel(_, span) => *span,
+            ParseError::ExpectedKeyword { span, .. } => *span,
+            ParseError::ExpectedDeclaration { span, .. } => *span,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Lex(e) => write!(f, "{}", e),
+            ParseError::UnexpectedToken { expected, found, .. } => {
+                write!(f, "Expected {:?}, got {:?}", expected, found)
+            }
+            ParseError::ExpectedExpression { found, value, .. } => {
+                write!(f, "Expected expression, got {:?} \"{}\"", found, value)
+            }
+            ParseError::ConstWithoutValue(name, _) => {
+                write!(f, "Cannot declare constant {} without an initial value", name)
+            }
+            ParseError::InvalidNumber(value, _) => write!(f, "Invalid number literal \"{}\"", value),
+            ParseError::TooDeeplyNested(max, _) => write!(f, "Code is nested more than {} levels deep", max),
+            ParseError::SourceTooLong { length, max } => {
+                write!(f, "Source is {} bytes long, the limit is {}", length, max)
+            }
+            ParseError::NotAtTopLevel(keyword, _) => {
+                write!(f, "{} is only allowed at the top level of a module", keyword)
+            }
+            ParseError::ExpectedKeyword { keyword, found, value, .. } => {
+                write!(f, "Expected {}, got {:?} \"{}\"", keyword, found, value)
+            }
+            ParseError::ExpectedDeclaration { found, value, .. } => {
+                write!(f, "Expected a declaration to export, got {:?} \"{}\"", found, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<LexError> for ParseError {
+    fn from(e: LexError) -> Self {
+        ParseError::Lex(e)
+    }
+}
 
 #[derive(Debug)]
 pub struct Parser {
-    tokens: Vec<Token>,
+    tokens: VecDeque<Token>,
+    // span of the most recently eaten token, used to close off node spans
+    prev: Span,
+    // how deeply the statement or expression being parsed is nested
+    depth: usize,
+    max_depth: usize,
 }
 
 impl Parser {
-    pub fn produce_ast(source_code: &str) -> Program {
-        let tokens = tokenize(source_code);
-        let mut parser = Parser { tokens };
+    pub fn produce_ast(source_code: &str) -> Result<Program, ParseError> {
+        Parser::produce_ast_with_limits(source_code, &Limits::default())
+    }
+
+    /// Like [`produce_ast`](Parser::produce_ast), for source that has to stay
+    /// within the parsing `limits`.
+    pub fn produce_ast_with_limits(source_code: &str, limits: &Limits) -> Result<Program, ParseError> {
+        check_source_len(source_code, limits)?;
+
+        let tokens = tokenize(source_code)?;
+        let mut parser = Parser::new(tokens, limits.max_parse_depth);
         let mut body = Vec::new();
 
         while !parser.is_eof() {
-            let stmt = parser.parse_stmt();
+            let stmt = parser.parse_stmt()?;
             body.push(stmt);
         }
 
-        Program { body }
+        let span = Span::new(0, source_code.len(), 1, 1);
+        Ok(Program { body, span })
+    }
+
+    /// Parses the whole source, recovering from syntax errors instead of
+    /// stopping at the first one. Statements that failed to parse show up as
+    /// `Statement::Error` in the returned program.
+    pub fn produce_ast_recovering(source_code: &str) -> (Program, Vec<ParseError>) {
+        Parser::produce_ast_recovering_with_limits(source_code, &Limits::default())
+    }
+
+    /// Like [`produce_ast_recovering`](Parser::produce_ast_recovering), for
+    /// source that has to stay within the parsing `limits`.
+    pub fn produce_ast_recovering_with_limits(source_code: &str, limits: &Limits) -> (Program, Vec<ParseError>) {
+        let span = Span::new(0, source_code.len(), 1, 1);
+        if let Err(error) = check_source_len(source_code, limits) {
+            return (Program { body: Vec::new(), span }, vec![error]);
+        }
+
+        let (tokens, lex_errors) = tokenize_recovering(source_code);
+        let mut errors: Vec<ParseError> = lex_errors.into_iter().map(ParseError::from).collect();
+        let mut parser = Parser::new(tokens, limits.max_parse_depth);
+        let mut body = Vec::new();
+
+        while !parser.is_eof() {
+            let remaining = parser.tokens.len();
+            let start = parser.at().span;
+
+            match parser.parse_stmt() {
+                Ok(stmt) => body.push(stmt),
+                // the parser gave up halfway through the nesting, what follows can't be made sense of
+                Err(e @ ParseError::TooDeeplyNested(..)) => {
+                    errors.push(e);
+                    break;
+                },
+                Err(e) => {
+                    errors.push(e);
+
+                    // always make progress, even if the error was on the very first token
+                    let skipped = if parser.tokens.len() == remaining {
+                        Some(parser.eat().t)
+                    } else {
+                        None
+                    };
+                    if !matches!(skipped, Some(TokenType::Semicolon | TokenType::CloseBrace)) {
+                        parser.synchronize();
+                    }
+
+                    body.push(StatementOrExpression::Statement(Statement::Error(start.to(parser.prev))));
+                }
+            }
+        }
+
+        errors.sort_by_key(|e| e.span().start);
+
+        (Program { body, span }, errors)
+    }
+
+    fn new(mut tokens: Vec<Token>, max_depth: usize) -> Self {
+        // doc comments only matter to tooling
+        tokens.retain(|token| token.t != TokenType::DocComment);
+        Parser { tokens: tokens.into(), prev: Span::default(), depth: 0, max_depth }
+    }
+
+    // Runs `parse` one level of nesting deeper. Every construct that can nest
+    // goes through here, so deeply nested source fails with an error instead of
+    // overflowing the stack.
+    fn nested<T>(&mut self, parse: fn(&mut Parser) -> Result<T, ParseError>) -> Result<T, ParseError> {
+        self.chain(|parser| {
+            parser.descend()?;
+            parse(parser)
+        })
+    }
+
+    // Runs `parse`, which goes one level deeper for every link of a chain like
+    // `a + b + c` or `a.b().c`, and comes back up afterwards. Each link wraps
+    // everything before it, so a long chain nests as deep as a long
+    // parenthesized expression once it runs.
+    fn chain<T>(&mut self, parse: impl FnOnce(&mut Parser) -> Result<T, ParseError>) -> Result<T, ParseError> {
+        let depth = self.depth;
+        let result = parse(self);
+        self.depth = depth;
+        result
+    }
+
+    fn descend(&mut self) -> Result<(), ParseError> {
+        if self.depth >= self.max_depth {
+            return Err(ParseError::TooDeeplyNested(self.max_depth, self.at().span));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    // Panic mode recovery: skip ahead to something that looks like the start of the next statement
+    fn synchronize(&mut self) {
+        loop {
+            match self.at().t {
+                TokenType::EOF => return,
+                TokenType::Let
+                | TokenType::Const
+                | TokenType::Fn
+                | TokenType::Return
+                | TokenType::If
+                | TokenType::While
+                | TokenType::For
+                | TokenType::Break
+                | TokenType::Continue
+                | TokenType::Import => return,
+                TokenType::Semicolon | TokenType::CloseBrace => {
+                    self.eat();
+                    return;
+                }
+                _ => {
+                    self.eat();
+                }
+            }
+        }
     }
 
     fn at(&self) -> &Token {
@@ -31,15 +261,51 @@ impl Parser {
     }
 
     fn eat(&mut self) -> Token {
-        self.tokens.remove(0)
+        // never consume the EOF token, so a truncated source can't run us off the end
+        if self.is_eof() {
+            return self.tokens[0].clone();
+        }
+        let token = self.tokens.pop_front().expect("the EOF token is never eaten");
+        self.prev = token.span;
+        token
     }
 
-    fn expect(&mut self, t: TokenType) -> Token {
-        let token = self.eat();
-        if token.t != t {
-            panic!("Expected {:?}, got {:?}", t, token.t);
+    fn expect(&mut self, t: TokenType) -> Result<Token, ParseError> {
+        // leave a mismatched token in place so error recovery can look at it
+        if self.at().t != t {
+            let token = self.at();
+            return Err(ParseError::UnexpectedToken {
+                expected: t,
+                found: token.t,
+                value: token.value.clone(),
+                span: token.span,
+            });
         }
-        token
+        Ok(self.eat())
+    }
+
+    // an identifier used as a keyword, `from` or `export`
+    fn expect_keyword(&mut self, keyword: &str) -> Result<Token, ParseError> {
+        let token = self.at();
+        if token.t != TokenType::Identifier || token.value != keyword {
+            return Err(ParseError::ExpectedKeyword {
+                keyword: keyword.to_string(),
+                found: token.t,
+                value: token.value.clone(),
+                span: token.span,
+            });
+        }
+        Ok(self.eat())
+    }
+
+    fn is_export(&self) -> bool {
+        self.at().t == TokenType::Identifier
+            && self.at().value == "export"
+            && matches!(self.peek_type(1), TokenType::Let | TokenType::Const | TokenType::Fn)
+    }
+
+    fn peek_type(&self, offset: usize) -> TokenType {
+        self.tokens.get(offset).map(|t| t.t).unwrap_or(TokenType::EOF)
     }
 
     fn is_eof(&self) -> bool {
@@ -47,7 +313,7 @@ impl Parser {
         self.tokens[0].t == TokenType::EOF
     }
 
-    fn parse_stmt(&mut self) -> StatementOrExpression {
+    fn parse_stmt(&mut self) -> Result<StatementOrExpression, ParseError> {
         let current = self.at();
         match current.t {
             TokenType::Let => {
@@ -56,71 +322,357 @@ impl Parser {
             TokenType::Const => {
                 self.parse_var_decleration()
             }
+            // `fn name() {}` declares, a nameless `fn () {}` is just an expression
+            TokenType::Fn if self.peek_type(1) == TokenType::Identifier => {
+                self.parse_fn_declaration()
+            }
+            TokenType::Return => {
+                self.parse_return_stmt()
+            }
+            TokenType::If => {
+                Ok(StatementOrExpression::Statement(Statement::If(self.parse_if_stmt()?)))
+            }
+            TokenType::While => {
+                self.parse_while_stmt()
+            }
+            TokenType::For => {
+                self.parse_for_stmt()
+            }
+            TokenType::Break | TokenType::Continue => {
+                let keyword = self.eat();
+                if self.at().t == TokenType::Semicolon {
+                    self.eat();
+                }
 
-            _ => self.parse_expr()
-        }
+                let stmt = match keyword.t {
+                    TokenType::Break => Statement::Break(keyword.span),
+                    _ => Statement::Continue(keyword.span),
+                };
+                Ok(StatementOrExpression::Statement(stmt))
+            }
+            // modules import and export at the top level, before anything is nested
+            TokenType::Import if self.depth > 0 => {
+                let span = current.span;
+                // parsed anyway, so error recovery carries on after the whole statement
+                self.parse_import_stmt()?;
+                Err(ParseError::NotAtTopLevel("import".to_string(), span))
+            }
+            TokenType::Import => {
+                self.parse_import_stmt()
+            }
+            // `export` is only special in front of a declaration, elsewhere it's a normal name
+            TokenType::Identifier if self.is_export() && self.depth > 0 => {
+                let span = current.span;
+                self.parse_export_declaration()?;
+                Err(ParseError::NotAtTopLevel("export".to_string(), span))
+            }
+            TokenType::Identifier if self.is_export() => {
+                self.parse_export_declaration()
+            }
+            // a brace at the start of a statement opens a block, object literals
+            // are only parsed where a value is expected
+            TokenType::OpenBrace => {
+                Ok(StatementOrExpression::Statement(Statement::Block(self.parse_block()?)))
+            }
+
+            _ => {
+                let expr = self.parse_expr()?;
 
+                // expression statements may optionally be terminated
+                if self.at().t == TokenType::Semicolon {
+                    self.eat();
+                }
 
-        // return self.parse_expr();
+                Ok(StatementOrExpression::Expression(expr))
+            }
+        }
     }
 
-    fn parse_var_decleration(&mut self) -> StatementOrExpression {
-        let is_const = self.eat().t == TokenType::Const;
-        let identifier = self.expect(TokenType::Identifier);
-        
+    fn parse_var_decleration(&mut self) -> Result<StatementOrExpression, ParseError> {
+        let keyword = self.eat();
+        let is_const = keyword.t == TokenType::Const;
+        let token = self.expect(TokenType::Identifier)?;
+        let identifier = Identifier { symbol: token.value, span: token.span };
+
         if self.at().t == TokenType::Semicolon {
-            self.eat(); // expect semicolon
-            if is_const { 
-                panic!("Cannot declare a constant without an initial value");
+            if is_const {
+                return Err(ParseError::ConstWithoutValue(identifier.symbol, identifier.span));
             }
+            self.eat(); // expect semicolon
 
-            return StatementOrExpression::Statement(
+            return Ok(StatementOrExpression::Statement(
                 Statement::VariableDecleration(
-                    VariableDecleration::new(identifier.value, None, is_const)
+                    VariableDecleration::new(identifier, None, is_const, keyword.span.to(self.prev))
                 )
-            );
+            ));
         }
 
-        self.expect(TokenType::Equals);
-        let expr = match self.parse_expr() {
-            StatementOrExpression::Expression(expr) => expr,
-            _ => panic!("Expected expression")
-        };
-        self.expect(TokenType::Semicolon);
+        self.expect(TokenType::Equals)?;
+        let expr = self.parse_expr()?;
+        self.expect(TokenType::Semicolon)?;
 
-        StatementOrExpression::Statement(
+        Ok(StatementOrExpression::Statement(
             Statement::VariableDecleration(
-                VariableDecleration::new(identifier.value, Some(expr), is_const)
+                VariableDecleration::new(identifier, Some(expr), is_const, keyword.span.to(self.prev))
             )
-        )
+        ))
+    }
+
+    fn parse_fn_declaration(&mut self) -> Result<StatementOrExpression, ParseError> {
+        let keyword = self.eat(); // eat fn
+        let name = self.expect(TokenType::Identifier)?;
+        let parameters = self.parse_params()?;
+        let body = self.parse_block_body()?;
+
+        Ok(StatementOrExpression::Statement(
+            Statement::FunctionDeclaration(FunctionDeclaration {
+                name: Identifier { symbol: name.value, span: name.span },
+                parameters,
+                body: Rc::new(body),
+                span: keyword.span.to(self.prev),
+            })
+        ))
+    }
+
+    // import { a, b } from "./util.avii";
+    fn parse_import_stmt(&mut self) -> Result<StatementOrExpression, ParseError> {
+        let keyword = self.eat(); // eat import
+        self.expect(TokenType::OpenBrace)?;
+
+        let mut names = Vec::new();
+        while self.at().t != TokenType::CloseBrace {
+            let name = self.expect(TokenType::Identifier)?;
+            names.push(Identifier { symbol: name.value, span: name.span });
+
+            if self.at().t != TokenType::CloseBrace {
+                self.expect(TokenType::Comma)?;
+            }
+        }
+
+        self.expect(TokenType::CloseBrace)?;
+        self.expect_keyword("from")?;
+        let path = self.expect(TokenType::String)?;
+        self.expect(TokenType::Semicolon)?;
+
+        Ok(StatementOrExpression::Statement(
+            Statement::Import(ImportStatement {
+                names,
+                path: path.value,
+                path_span: path.span,
+                span: keyword.span.to(self.prev),
+            })
+        ))
+    }
 
+    fn parse_export_declaration(&mut self) -> Result<StatementOrExpression, ParseError> {
+        let keyword = self.expect_keyword("export")?;
+        let declaration = match self.at().t {
+            TokenType::Let | TokenType::Const => self.parse_var_decleration()?,
+            TokenType::Fn if self.peek_type(1) == TokenType::Identifier => self.parse_fn_declaration()?,
+            _ => {
+                let token = self.at();
+                return Err(ParseError::ExpectedDeclaration {
+                    found: token.t,
+                    value: token.value.clone(),
+                    span: token.span,
+                });
+            }
+        };
+        let StatementOrExpression::Statement(declaration) = declaration else {
+            unreachable!("declarations are statements");
+        };
 
+        Ok(StatementOrExpression::Statement(
+            Statement::Export(ExportDeclaration {
+                declaration: Box::new(declaration),
+                span: keyword.span.to(self.prev),
+            })
+        ))
     }
 
-    fn parse_expr(&mut self) -> StatementOrExpression {
-        self.parse_assignment_expr()
+    fn parse_fn_expr(&mut self) -> Result<Expression, ParseError> {
+        let keyword = self.eat(); // eat fn
+        let parameters = self.parse_params()?;
+        let body = self.parse_block_body()?;
+
+        Ok(Expression::Function(FunctionExpr {
+            parameters,
+            body: Rc::new(body),
+            span: keyword.span.to(self.prev),
+        }))
     }
 
-    fn parse_object_expr(&mut self) -> StatementOrExpression {
+    // (a, b, c)
+    fn parse_params(&mut self) -> Result<Vec<Identifier>, ParseError> {
+        self.expect(TokenType::OpenParen)?;
+
+        let mut params = Vec::new();
+        while self.at().t != TokenType::CloseParen {
+            let param = self.expect(TokenType::Identifier)?;
+            params.push(Identifier { symbol: param.value, span: param.span });
+
+            if self.at().t != TokenType::CloseParen {
+                self.expect(TokenType::Comma)?;
+            }
+        }
+
+        self.expect(TokenType::CloseParen)?;
+
+        Ok(params)
+    }
+
+    // { stmt; stmt; ... }
+    fn parse_block_body(&mut self) -> Result<Vec<StatementOrExpression>, ParseError> {
+        self.expect(TokenType::OpenBrace)?;
+
+        let mut body = Vec::new();
+        while !self.is_eof() && self.at().t != TokenType::CloseBrace {
+            body.push(self.nested(Parser::parse_stmt)?);
+        }
+
+        self.expect(TokenType::CloseBrace)?;
+
+        Ok(body)
+    }
+
+    fn parse_block(&mut self) -> Result<BlockStatement, ParseError> {
+        let start = self.at().span;
+        let body = self.parse_block_body()?;
+
+        Ok(BlockStatement {
+            body,
+            span: start.to(self.prev),
+        })
+    }
+
+    fn parse_if_stmt(&mut self) -> Result<IfStatement, ParseError> {
+        let keyword = self.eat(); // eat if
+        let condition = self.parse_expr()?;
+        let consequent = self.parse_block()?;
+
+        let alternate = match self.at().t {
+            TokenType::Else => {
+                self.eat();
+                match self.at().t {
+                    TokenType::If => Some(Box::new(Statement::If(self.nested(Parser::parse_if_stmt)?))),
+                    _ => Some(Box::new(Statement::Block(self.parse_block()?))),
+                }
+            }
+            _ => None,
+        };
+
+        Ok(IfStatement {
+            condition,
+            consequent,
+            alternate,
+            span: keyword.span.to(self.prev),
+        })
+    }
+
+    fn parse_while_stmt(&mut self) -> Result<StatementOrExpression, ParseError> {
+        let keyword = self.eat(); // eat while
+        let condition = self.parse_expr()?;
+        let body = self.parse_block()?;
+
+        Ok(StatementOrExpression::Statement(Statement::While(WhileStatement {
+            condition,
+            body,
+            span: keyword.span.to(self.prev),
+        })))
+    }
+
+    fn parse_for_stmt(&mut self) -> Result<StatementOrExpression, ParseError> {
+        let keyword = self.eat(); // eat for
+        self.expect(TokenType::OpenParen)?;
+
+        // a variable declaration eats its own semicolon
+        let init = match self.at().t {
+            TokenType::Semicolon => {
+                self.eat();
+                None
+            }
+            TokenType::Let | TokenType::Const => Some(Box::new(self.parse_var_decleration()?)),
+            _ => {
+                let expr = self.parse_expr()?;
+                self.expect(TokenType::Semicolon)?;
+                Some(Box::new(StatementOrExpression::Expression(expr)))
+            }
+        };
+
+        let condition = match self.at().t {
+            TokenType::Semicolon => None,
+            _ => Some(self.parse_expr()?),
+        };
+        self.expect(TokenType::Semicolon)?;
+
+        let update = match self.at().t {
+            TokenType::CloseParen => None,
+            _ => Some(self.parse_expr()?),
+        };
+        self.expect(TokenType::CloseParen)?;
+
+        let body = self.parse_block()?;
+
+        Ok(StatementOrExpression::Statement(Statement::For(ForStatement {
+            init,
+            condition,
+            update,
+            body,
+            span: keyword.span.to(self.prev),
+        })))
+    }
+
+    fn parse_return_stmt(&mut self) -> Result<StatementOrExpression, ParseError> {
+        let keyword = self.eat(); // eat return
+
+        let value = match self.at().t {
+            TokenType::Semicolon | TokenType::CloseBrace | TokenType::EOF => None,
+            _ => Some(self.parse_expr()?),
+        };
+
+        if self.at().t == TokenType::Semicolon {
+            self.eat();
+        }
+
+        Ok(StatementOrExpression::Statement(
+            Statement::Return(ReturnStatement {
+                value,
+                span: keyword.span.to(self.prev),
+            })
+        ))
+    }
+
+    fn parse_expr(&mut self) -> Result<Expression, ParseError> {
+        self.nested(Parser::parse_assignment_expr)
+    }
+
+    fn parse_object_expr(&mut self) -> Result<Expression, ParseError> {
 
         if self.at().t != TokenType::OpenBrace {
-            return self.parse_additive_expr();
+            return self.parse_logical_or_expr();
         }
 
-        self.eat(); // eat the open brace
+        let open = self.eat(); // eat the open brace
 
         let mut properties = Vec::new();
 
         while !self.is_eof() && self.at().t != TokenType::CloseBrace {
 
-            let key = self.expect(TokenType::Identifier).value;
+            // keys are identifiers, or strings for anything that isn't a valid identifier.
+            // Keywords work too, but only with a value, there's no variable to take it from.
+            let key = match self.at().t {
+                TokenType::String => self.eat(),
+                t if t.is_keyword() && self.peek_type(1) == TokenType::Colon => self.eat(),
+                _ => self.expect(TokenType::Identifier)?,
+            };
 
             // { key, .. }
             if self.at().t == TokenType::Comma {
                 self.eat();
                 properties.push(Property {
-                    key,
-                    value: None
+                    key: key.value,
+                    value: None,
+                    span: key.span,
                 });
                 continue;
             }
@@ -128,260 +680,326 @@ impl Parser {
             // { key }
             if self.at().t == TokenType::CloseBrace {
                 properties.push(Property {
-                    key,
-                    value: None
+                    key: key.value,
+                    value: None,
+                    span: key.span,
                 });
                 continue;
             }
-            
+
             // { key: val, ... }
-            self.expect(TokenType::Colon);
-            let value = match self.parse_expr() {
-                StatementOrExpression::Expression(expr) => expr,
-                _ => panic!("Expected expression")
-            };
+            self.expect(TokenType::Colon)?;
+            let value = self.parse_expr()?;
 
-            properties.push(Property {
-                key,
-                value: Some(Box::new(value))
-            });
+            properties.push(Property::new(key.value, value, key.span.to(self.prev)));
 
             if self.at().t != TokenType::CloseBrace {
-                self.expect(TokenType::Comma);
+                self.expect(TokenType::Comma)?;
             }
 
         }
 
-        self.expect(TokenType::CloseBrace);
-        
-        StatementOrExpression::Expression(Expression::ObjectLiteral(
-            ObjectLiteral {
-                properties
-            }
-        ))
-
-
-        //     let key = self.expect(TokenType::Identifier);
-        //     self.expect(TokenType::Colon);
-        //     let value = match self.parse_expr() {
-        //         StatementOrExpression::Expression(expr) => expr,
-        //         _ => panic!("Expected expression")
-        //     };
-
-        //     properties.push((key.value, value));
-
-        //     if self.at().t == TokenType::Comma {
-        //         self.eat();
-        //     }
-        // }
-
-        // self.expect(TokenType::RBrace);
+        self.expect(TokenType::CloseBrace)?;
 
+        Ok(Expression::ObjectLiteral(
+            ObjectLiteral::new(properties, open.span.to(self.prev))
+        ))
     }
 
-    fn parse_assignment_expr(&mut self) -> StatementOrExpression {
-        let left = self.parse_object_expr();
+    fn parse_assignment_expr(&mut self) -> Result<Expression, ParseError> {
+        let left = self.parse_object_expr()?;
 
         if self.at().t == TokenType::Equals {
             self.eat(); // advance past equals
-            let left = match left {
-                StatementOrExpression::Expression(expr) => expr,
-                _ => panic!("Expected expression")
-            };
-            let value = match self.parse_assignment_expr() {
-                StatementOrExpression::Expression(expr) => expr,
-                _ => panic!("Expected expression")
-            };
-            // self.expect(TokenType::Semicolon);
-            return StatementOrExpression::Expression(
-                Expression::Assignment(
-                    Assignment::new(left, value)
-                )
-            );
+            let value = self.nested(Parser::parse_assignment_expr)?;
+            return Ok(Expression::Assignment(
+                Assignment::new(left, value)
+            ));
         }
 
-        left
+        Ok(left)
     }
 
-    fn parse_additive_expr(&mut self) -> StatementOrExpression {
-        let mut left = self.parse_multiplicitive_expr();
+    // Parses a left associative chain of binary `operators`, with `operand` parsing
+    // the next level of precedence up
+    fn parse_binary_expr(
+        &mut self,
+        operators: &[&str],
+        operand: fn(&mut Parser) -> Result<Expression, ParseError>,
+    ) -> Result<Expression, ParseError> {
+        self.chain(|parser| {
+            let mut left = operand(parser)?;
+
+            while parser.at_operator(operators) {
+                parser.descend()?;
+                let operator = parser.eat();
+                let right = operand(parser)?;
+
+                let span = left.span().to(right.span());
+                left = Expression::Binary(Binary {
+                    left: Box::new(left),
+                    operator: operator.value,
+                    right: Box::new(right),
+                    span,
+                });
+            }
 
-        while self.at().value == "+" || self.at().value == "-" {
-            let operator = self.eat();
-            let right = self.parse_multiplicitive_expr();
+            Ok(left)
+        })
+    }
 
-            let extracted_left = match left {
-                StatementOrExpression::Expression(e) => e,
-                _ => panic!("Expected expression"),
-            };
+    fn at_operator(&self, operators: &[&str]) -> bool {
+        self.at().t == TokenType::BinaryOperator && operators.contains(&self.at().value.as_str())
+    }
 
-            let extracted_right = match right {
-                StatementOrExpression::Expression(e) => e,
-                _ => panic!("Expected expression"),
-            };
+    fn parse_logical_or_expr(&mut self) -> Result<Expression, ParseError> {
+        self.parse_binary_expr(&["||"], Parser::parse_logical_and_expr)
+    }
 
-            left = StatementOrExpression::Expression(Expression::Binary(Binary {
-                left: Box::new(extracted_left),
-                operator: operator.value,
-                right: Box::new(extracted_right),
-            }));
-        }
+    fn parse_logical_and_expr(&mut self) -> Result<Expression, ParseError> {
+        self.parse_binary_expr(&["&&"], Parser::parse_equality_expr)
+    }
 
-        left
+    fn parse_equality_expr(&mut self) -> Result<Expression, ParseError> {
+        self.parse_binary_expr(&["==", "!="], Parser::parse_comparison_expr)
     }
 
-    fn parse_multiplicitive_expr(&mut self) -> StatementOrExpression {
-        let mut left = self.parse_call_member_expr();
+    fn parse_comparison_expr(&mut self) -> Result<Expression, ParseError> {
+        self.parse_binary_expr(&["<", "<=", ">", ">="], Parser::parse_additive_expr)
+    }
 
-        while self.at().value == "/" || self.at().value == "*" || self.at().value == "%" {
-            let operator = self.eat();
-            let right = self.parse_call_member_expr();
+    fn parse_additive_expr(&mut self) -> Result<Expression, ParseError> {
+        self.parse_binary_expr(&["+", "-"], Parser::parse_multiplicitive_expr)
+    }
 
-            let extracted_left = match left {
-                StatementOrExpression::Expression(e) => e,
-                _ => panic!("Expected expression"),
-            };
+    fn parse_multiplicitive_expr(&mut self) -> Result<Expression, ParseError> {
+        self.parse_binary_expr(&["/", "*", "%"], Parser::parse_unary_expr)
+    }
 
-            let extracted_right = match right {
-                StatementOrExpression::Expression(e) => e,
-                _ => panic!("Expected expression"),
-            };
+    // -x, !x
+    fn parse_unary_expr(&mut self) -> Result<Expression, ParseError> {
+        if self.at().t == TokenType::Bang || self.at_operator(&["-"]) {
+            let operator = self.eat();
+            let argument = self.nested(Parser::parse_unary_expr)?;
 
-            left = StatementOrExpression::Expression(Expression::Binary(Binary {
-                left: Box::new(extracted_left),
+            let span = operator.span.to(argument.span());
+            return Ok(Expression::Unary(Unary {
                 operator: operator.value,
-                right: Box::new(extracted_right),
+                argument: Box::new(argument),
+                span,
             }));
         }
 
-        left
+        self.parse_call_member_expr()
     }
 
     // foo.x()
-    fn parse_call_member_expr(&mut self) -> StatementOrExpression {
-        let member = self.parse_member_expr();
-
-        if self.at().t == TokenType::OpenParen {
-            return StatementOrExpression::Expression(self.parse_call_expr(member));
-        }
+    // calls and member accesses can follow each other in any order: `make().items[0].get()`
+    fn parse_call_member_expr(&mut self) -> Result<Expression, ParseError> {
+        self.chain(|parser| {
+            let mut expr = parser.parse_member_expr()?;
+
+            while parser.at().t == TokenType::OpenParen {
+                parser.descend()?;
+                expr = parser.parse_call_expr(Box::new(expr))?;
+                expr = parser.parse_member_access(expr)?;
+            }
 
-        StatementOrExpression::Expression(*member)
+            Ok(expr)
+        })
     }
 
-    fn parse_call_expr(&mut self, caller: Box<Expression>) -> Expression {
-        let mut call_expr = Expression::Call(
+    fn parse_call_expr(&mut self, caller: Box<Expression>) -> Result<Expression, ParseError> {
+        let arguments = self.parse_args()?;
+        let span = caller.span().to(self.prev);
+
+        Ok(Expression::Call(
             CallExpr {
                 caller,
-                arguments: self.parse_args(),
+                arguments,
+                span,
             }
-        );
-
-        if self.at().t == TokenType::OpenParen {
-            call_expr = self.parse_call_expr(Box::new(call_expr));
-        }
-
-        call_expr
+        ))
     }
 
-    fn parse_args(&mut self) -> Vec<Expression> {
-        self.expect(TokenType::OpenParen);
+    fn parse_args(&mut self) -> Result<Vec<Expression>, ParseError> {
+        self.expect(TokenType::OpenParen)?;
 
         let args = if self.at().t == TokenType::CloseParen {
             Vec::new()
         } else {
-            self.parse_argument_list()
+            self.parse_argument_list()?
         };
 
-        self.expect(TokenType::CloseParen);
+        self.expect(TokenType::CloseParen)?;
 
-        args
+        Ok(args)
     }
 
-    fn parse_argument_list(&mut self) -> Vec<Expression> {
-        let mut args = Vec::new();
-        args.push(self.parse_expr());
+    fn parse_argument_list(&mut self) -> Result<Vec<Expression>, ParseError> {
+        let mut args = vec![self.parse_expr()?];
 
         while self.at().t == TokenType::Comma {
             self.eat();
-            args.push(self.parse_assignment_expr());
+            args.push(self.parse_expr()?);
         }
 
-        args.into_iter().map(|arg| match arg {
-            StatementOrExpression::Expression(expr) => expr,
-            _ => panic!("Expected expression")
-        }).collect()
+        Ok(args)
     }
 
-    fn parse_member_expr(&mut self) -> Box<Expression> {
-        let mut object = self.parse_primary_expr();
+    fn parse_member_expr(&mut self) -> Result<Expression, ParseError> {
+        let object = self.parse_primary_expr()?;
+        self.parse_member_access(object)
+    }
 
+    // any number of `.key` and `[expr]` following `object`, each one level
+    // deeper, until the chain they belong to ends
+    fn parse_member_access(&mut self, mut object: Expression) -> Result<Expression, ParseError> {
         while self.at().t == TokenType::Dot || self.at().t == TokenType::OpenBracket {
+            self.descend()?;
             let operator = self.eat(); // . or [
 
-            let computed = match operator.t {
-                TokenType::OpenBracket => true,
-                _ => false,
-            };
+            let computed = operator.t == TokenType::OpenBracket;
 
             let property = match operator.t {
+                // `o.if` is as good a name as `o.x`
+                TokenType::Dot if self.at().t.is_keyword() => {
+                    let token = self.eat();
+                    Expression::Identifier(Identifier { symbol: token.value, span: token.span })
+                }
+                // only a name can follow the dot, `o.0` or `o.(k)` need brackets
                 TokenType::Dot => {
-                    match self.parse_primary_expr() {
-                        StatementOrExpression::Expression(id) => Some(id),
-                        _ => panic!("Expected expression")
-                    }
-                },
+                    let token = self.expect(TokenType::Identifier)?;
+                    Expression::Identifier(Identifier { symbol: token.value, span: token.span })
+                }
                 _ => {
-                    let p = match self.parse_expr() {
-                        StatementOrExpression::Expression(expr) => Some(expr),
-                        _ => panic!("Expected expression")
-                    };
-    
-                    self.expect(TokenType::CloseBracket);
-
+                    let p = self.parse_expr()?;
+                    self.expect(TokenType::CloseBracket)?;
                     p
                 }
             };
 
-            let extracted_object = match object {
-                StatementOrExpression::Expression(expr) => expr,
-                _ => panic!("Expected expression")
-            };
-
-            object = StatementOrExpression::Expression(Expression::Member(MemberExpr {
-                object: Box::new(extracted_object),
-                property: Box::new(property.unwrap()),
+            let span = object.span().to(self.prev);
+            object = Expression::Member(MemberExpr {
+                object: Box::new(object),
+                property: Box::new(property),
                 computed,
-            }));
-
+                span,
+            });
         }
 
-        match object {
-            StatementOrExpression::Expression(expr) => Box::new(expr),
-            _ => panic!("Expected expression")
-        }
+        Ok(object)
     }
 
-    fn parse_primary_expr(&mut self) -> StatementOrExpression {
-        let tk = self.at().t.clone();
+    fn parse_primary_expr(&mut self) -> Result<Expression, ParseError> {
+        let tk = self.at().t;
 
         match tk {
             TokenType::Number => {
                 let token = self.eat();
-                let value = token.value.parse::<f64>().unwrap();
-                return StatementOrExpression::Expression(Expression::NumericLiteral( NumericLiteral { value }));
+                let value = token.value.parse::<f64>()
+                    .map_err(|_| ParseError::InvalidNumber(token.value.clone(), token.span))?;
+                Ok(Expression::NumericLiteral( NumericLiteral { value, span: token.span }))
+            }
+            TokenType::String => {
+                let token = self.eat();
+                Ok(Expression::StringLiteral(StringLiteral { value: token.value, span: token.span }))
             }
             TokenType::Identifier => {
                 let token = self.eat();
                 let symbol = token.value;
-                return StatementOrExpression::Expression(Expression::Identifier( Identifier { symbol }));
+                Ok(Expression::Identifier( Identifier { symbol, span: token.span }))
             }
+            TokenType::Fn => self.parse_fn_expr(),
+            TokenType::OpenBracket => self.parse_array_expr(),
             TokenType::OpenParen => {
                 self.eat();
-                let expr = self.parse_expr();
-                self.expect(TokenType::CloseParen);
-                return expr;
+                let expr = self.parse_expr()?;
+                self.expect(TokenType::CloseParen)?;
+                Ok(expr)
+            }
+            _ => {
+                let token = self.at();
+                Err(ParseError::ExpectedExpression { found: token.t, value: token.value.clone(), span: token.span })
+            }
+        }
+    }
+
+    // [a, b, c]
+    fn parse_array_expr(&mut self) -> Result<Expression, ParseError> {
+        let open = self.eat(); // eat the open bracket
+
+        let mut elements = Vec::new();
+
+        while !self.is_eof() && self.at().t != TokenType::CloseBracket {
+            elements.push(self.parse_expr()?);
+
+            if self.at().t != TokenType::CloseBracket {
+                self.expect(TokenType::Comma)?;
             }
-            _ => panic!("Unexpected token: {:?}", self.at()),
         }
+
+        self.expect(TokenType::CloseBracket)?;
+
+        Ok(Expression::ArrayLiteral(ArrayLiteral {
+            elements,
+            span: open.span.to(self.prev),
+        }))
     }
-}
\ No newline at end of file
+}
+
+fn check_source_len(source_code: &str, limits: &Limits) -> Result<(), ParseError> {
+    match limits.max_source_len {
+        Some(max) if source_code.len() > max => Err(ParseError::SourceTooLong { length: source_code.len(), max }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::EngineError, Engine};
+
+    // Chains used to parse flat and then overflow the stack of whichever
+    // backend walked them.
+    #[test]
+    fn long_chains_are_too_deeply_nested() {
+        let sources = [
+            format!("let x = {};", vec!["1"; 10_000].join(" + ")),
+            format!("let x = {};", vec!["true"; 10_000].join(" && ")),
+            format!("let o = {{}}; o{};", ".a".repeat(10_000)),
+            format!("let a = []; a{};", "[0]".repeat(10_000)),
+            format!("fn f() {{ f }} f{};", "()".repeat(10_000)),
+        ];
+
+        for source in &sources {
+            for mut engine in [Engine::new(), Engine::new().with_vm()] {
+                match engine.eval(source) {
+                    Err(EngineError::Parse(errors)) => {
+                        assert!(matches!(errors[..], [ParseError::TooDeeplyNested(128, _)]), "{:?}", errors)
+                    },
+                    other => panic!("expected a parse error, got {:?}", other),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_entry_point_checks_the_source_length() {
+        let limits = Limits { max_source_len: Some(8), ..Limits::default() };
+        let source = "let x = 1 + 2;";
+        let too_long = ParseError::SourceTooLong { length: source.len(), max: 8 };
+
+        assert_eq!(Parser::produce_ast_with_limits(source, &limits).unwrap_err(), too_long);
+        assert_eq!(Parser::produce_ast_recovering_with_limits(source, &limits).1, vec![too_long]);
+        assert!(Parser::produce_ast_with_limits("1 + 2", &limits).is_ok());
+    }
+
+    #[test]
+    fn chains_within_the_limit_run() {
+        let source = format!("{};", vec!["1"; 100].join(" + "));
+        for mut engine in [Engine::new(), Engine::new().with_vm()] {
+            assert_eq!(engine.eval(&source).unwrap().to_string(), "100");
+        }
+    }
+}
@@ -0,0 +1,674 @@
+use std::collections::HashMap;
+
+use crate::{
+    ast::{Expression, Identifier, Program, Statement, StatementOrExpression},
+    environment::Environment,
+    span::Span,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolveError {
+    UndefinedVariable(String, Span),
+    AlreadyDefined {
+        name: String,
+        span: Span,
+        previous: Option<Span>,
+    },
+    AssignToConstant {
+        name: String,
+        span: Span,
+        declared: Option<Span>,
+    },
+    // a call at `span` runs a function that uses `name` at `used`, before the
+    // variable is declared at `declared`
+    UsedBeforeDeclaration {
+        name: String,
+        span: Span,
+        used: Span,
+        declared: Span,
+    },
+}
+
+impl ResolveError {
+    pub fn span(&self) -> Span {
+        match self {
+            ResolveError::UndefinedVariable(_, span) => *span,
+            ResolveError::AlreadyDefined { span, .. } => *span,
+            ResolveError::AssignToConstant { span, .. } => *span,
+            ResolveError::UsedBeforeDeclaration { span, .. } => *span,
+        }
+    }
+}
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResolveError::UndefinedVariable(name, _) => write!(f, "Variable {} not defined", name),
+            ResolveError::AlreadyDefined { name, .. } => write!(f, "Variable {} already defined", name),
+            ResolveError::AssignToConstant { name, .. } => write!(f, "Cannot assign to constant {}", name),
+            ResolveError::UsedBeforeDeclaration { name, .. } => {
+                write!(f, "Variable {} is used before its declaration", name)
+            },
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolveWarning {
+    UnusedVariable(String, Span),
+    Shadowing {
+        name: String,
+        span: Span,
+        shadowed: Option<Span>,
+    },
+}
+
+impl ResolveWarning {
+    pub fn span(&self) -> Span {
+        match self {
+            ResolveWarning::UnusedVariable(_, span) => *span,
+            ResolveWarning::Shadowing { span, .. } => *span,
+        }
+    }
+}
+
+impl std::fmt::Display for ResolveWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResolveWarning::UnusedVariable(name, _) => write!(f, "Unused variable {}", name),
+            ResolveWarning::Shadowing { name, .. } => write!(f, "Variable {} shadows an outer variable", name),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Resolution {
+    pub errors: Vec<ResolveError>,
+    pub warnings: Vec<ResolveWarning>,
+}
+
+/// Checks every variable in `program` before it runs. `env` holds the globals
+/// the program will run with, like the builtins or values set by the host.
+///
+/// The pass only produces diagnostics, both backends still look variables up
+/// by name when the program runs.
+pub fn resolve(program: &Program, env: &Environment) -> Resolution {
+    let mut resolver = Resolver {
+        env,
+        scopes: Vec::new(),
+        function_depth: 0,
+        early_uses: Vec::new(),
+        resolution: Resolution::default(),
+    };
+
+    resolver.begin_scope();
+    resolver.predeclare(&program.body);
+    resolver.resolve_body(&program.body);
+    resolver.end_scope();
+
+    let mut resolution = resolver.resolution;
+    resolution.errors.sort_by_key(|e| e.span().start);
+    resolution.warnings.sort_by_key(|w| w.span().start);
+    resolution
+}
+
+struct Variable {
+    span: Span,
+    constant: bool,
+    // Code only sees a variable of its own function once the declaration ran,
+    // nested functions see everything in the enclosing scopes since they run later.
+    declared: bool,
+    used: bool,
+    // the path it was imported from, if it was
+    import: Option<String>,
+    // for a function, the variables its body uses before they are declared
+    early_uses: Vec<EarlyUse>,
+}
+
+// A variable a function body uses before the code around the function got to
+// its declaration. Calling the function before then fails, unless there is an
+// outer variable with the same name to fall back to.
+struct EarlyUse {
+    name: String,
+    // index of the scope the variable is declared in
+    scope: usize,
+    span: Span,
+}
+
+struct Scope {
+    variables: HashMap<String, Variable>,
+    // how many functions deep the scope is, 0 for the top level
+    function_depth: usize,
+}
+
+struct Resolver<'a> {
+    env: &'a Environment,
+    // the first scope is the program's top level
+    scopes: Vec<Scope>,
+    function_depth: usize,
+    // one list for every function being resolved, innermost last
+    early_uses: Vec<Vec<EarlyUse>>,
+    resolution: Resolution,
+}
+
+impl Resolver<'_> {
+    fn begin_scope(&mut self) {
+        self.scopes.push(Scope {
+            variables: HashMap::new(),
+            function_depth: self.function_depth,
+        });
+    }
+
+    // Everything declared directly in a scope is known up front, so nested
+    // functions can refer to variables declared after them.
+    fn predeclare(&mut self, body: &[StatementOrExpression]) {
+        for node in body {
+            if let StatementOrExpression::Statement(stmt) = node {
+                self.predeclare_stmt(stmt);
+            }
+        }
+    }
+
+    fn predeclare_stmt(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::VariableDecleration(var) => self.predeclare_variable(&var.identifier, var.constant),
+            Statement::FunctionDeclaration(decl) => self.predeclare_variable(&decl.name, false),
+            // imported names can't be assigned to
+            Statement::Import(import) => {
+                for name in &import.names {
+                    self.predeclare_variable(name, true);
+                }
+            },
+            Statement::Export(export) => self.predeclare_stmt(&export.declaration),
+            _ => {},
+        }
+    }
+
+    fn predeclare_variable(&mut self, identifier: &Identifier, constant: bool) {
+        let scope = self.scopes.last_mut().expect("there is always a scope");
+        scope.variables.entry(identifier.symbol.clone()).or_insert(Variable {
+            span: identifier.span,
+            constant,
+            declared: false,
+            used: false,
+            import: None,
+            early_uses: Vec::new(),
+        });
+    }
+
+    fn end_scope(&mut self) {
+        let scope = self.scopes.pop().expect("scopes are balanced");
+
+        // the top level's variables may still be read by the host
+        if self.scopes.is_empty() {
+            return;
+        }
+
+        for (name, variable) in scope.variables {
+            if variable.declared && !variable.used && !name.starts_with('_') {
+                self.resolution
+                    .warnings
+                    .push(ResolveWarning::UnusedVariable(name, variable.span));
+            }
+        }
+    }
+
+    fn is_top_level(&self) -> bool {
+        self.scopes.len() == 1
+    }
+
+    // the index of the innermost scope with a visible variable called `name`
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.lookup_below(name, self.scopes.len())
+    }
+
+    // like `lookup`, among the scopes outside of the one at `index`
+    fn lookup_below(&self, name: &str, index: usize) -> Option<usize> {
+        self.scopes[..index].iter().rposition(|scope| {
+            let nested = scope.function_depth < self.function_depth;
+            scope.variables.get(name).is_some_and(|v| v.declared || nested)
+        })
+    }
+
+    fn variable(&mut self, scope: usize, name: &str) -> &mut Variable {
+        self.scopes[scope].variables.get_mut(name).expect("looked up before")
+    }
+
+    fn declare(&mut self, identifier: &Identifier, constant: bool) {
+        let current = self.scopes.len() - 1;
+
+        // anything visible from here with the same name gets shadowed
+        let shadowed = match self.lookup(&identifier.symbol) {
+            Some(scope) if scope < current => Some(Some(self.scopes[scope].variables[&identifier.symbol].span)),
+            Some(_) => None,
+            None if !self.is_top_level() && self.env.get(&identifier.symbol).is_some() => Some(None),
+            None => None,
+        };
+        if let Some(shadowed) = shadowed {
+            if !identifier.symbol.starts_with('_') {
+                self.resolution.warnings.push(ResolveWarning::Shadowing {
+                    name: identifier.symbol.clone(),
+                    span: identifier.span,
+                    shadowed,
+                });
+            }
+        }
+
+        if self.is_top_level() && self.env.get(&identifier.symbol).is_some() {
+            self.resolution.errors.push(ResolveError::AlreadyDefined {
+                name: identifier.symbol.clone(),
+                span: identifier.span,
+                previous: None,
+            });
+        }
+
+        let scope = self.scopes.last_mut().expect("there is always a scope");
+        match scope.variables.get_mut(&identifier.symbol) {
+            Some(variable) if variable.declared => {
+                self.resolution.errors.push(ResolveError::AlreadyDefined {
+                    name: identifier.symbol.clone(),
+                    span: identifier.span,
+                    previous: Some(variable.span),
+                });
+            },
+            Some(variable) => {
+                variable.declared = true;
+                variable.constant = constant;
+                variable.span = identifier.span;
+            },
+            None => {
+                scope.variables.insert(identifier.symbol.clone(), Variable {
+                    span: identifier.span,
+                    constant,
+                    declared: true,
+                    used: false,
+                    import: None,
+                    early_uses: Vec::new(),
+                });
+            },
+        }
+    }
+
+    // the variable `identifier` just declared in the current scope, unless it
+    // clashed with something else declared under that name
+    fn declared(&mut self, identifier: &Identifier) -> Option<&mut Variable> {
+        let scope = self.scopes.last_mut().expect("there is always a scope");
+        scope
+            .variables
+            .get_mut(&identifier.symbol)
+            .filter(|v| v.span == identifier.span)
+    }
+
+    // Importing the same name from the same module again changes nothing, the
+    // engine links it to the same value.
+    fn declare_import(&mut self, identifier: &Identifier, path: &str) {
+        let scope = self.scopes.last().expect("there is always a scope");
+        let imported = scope.variables.get(&identifier.symbol).is_some_and(|v| v.declared && v.import.as_deref() == Some(path));
+        if imported {
+            return;
+        }
+
+        // An earlier eval may have imported it already. Linking tells whether
+        // it's the same value again, which is allowed.
+        let global = &identifier.symbol;
+        if self.is_top_level() && self.env.get(global).is_some() && self.env.is_constant(global) {
+            let scope = self.scopes.last_mut().expect("there is always a scope");
+            if let Some(variable) = scope.variables.get_mut(global).filter(|v| !v.declared) {
+                variable.declared = true;
+                variable.span = identifier.span;
+                variable.import = Some(path.to_string());
+                return;
+            }
+        }
+
+        self.declare(identifier, true);
+        if let Some(variable) = self.declared(identifier) {
+            variable.import = Some(path.to_string());
+        }
+    }
+
+    // A function body reading or writing the variable `name` in `scope`
+    // before it's declared, which only works if the function runs later.
+    fn note_early_use(&mut self, scope: usize, name: &str, span: Span) {
+        if self.scopes[scope].variables[name].declared {
+            return;
+        }
+        if let Some(uses) = self.early_uses.last_mut() {
+            uses.push(EarlyUse { name: name.to_string(), scope, span });
+        }
+    }
+
+    // a read of the variable `name`
+    fn use_variable(&mut self, name: &str, span: Span) {
+        match self.lookup(name) {
+            Some(scope) => {
+                self.variable(scope, name).used = true;
+                self.note_early_use(scope, name, span);
+            },
+            None if self.env.get(name).is_some() => {},
+            None => self
+                .resolution
+                .errors
+                .push(ResolveError::UndefinedVariable(name.to_string(), span)),
+        }
+    }
+
+    // a write to `identifier`, as part of the assignment at `span`
+    fn assign_variable(&mut self, identifier: &Identifier, span: Span) {
+        let name = &identifier.symbol;
+
+        let (constant, declared) = match self.lookup(name) {
+            Some(scope) => {
+                self.note_early_use(scope, name, identifier.span);
+                let variable = &self.scopes[scope].variables[name];
+                (variable.constant, Some(variable.span))
+            },
+            None if self.env.get(name).is_some() => (self.env.is_constant(name), None),
+            None => {
+                self.resolution
+                    .errors
+                    .push(ResolveError::UndefinedVariable(name.clone(), span));
+                return;
+            },
+        };
+
+        if constant {
+            self.resolution.errors.push(ResolveError::AssignToConstant {
+                name: name.clone(),
+                span,
+                declared,
+            });
+        }
+    }
+
+    fn resolve_body(&mut self, body: &[StatementOrExpression]) {
+        for node in body {
+            self.resolve_node(node);
+        }
+    }
+
+    fn resolve_node(&mut self, node: &StatementOrExpression) {
+        match node {
+            StatementOrExpression::Statement(stmt) => self.resolve_stmt(stmt),
+            StatementOrExpression::Expression(expr) => self.resolve_expr(expr),
+        }
+    }
+
+    fn resolve_block(&mut self, body: &[StatementOrExpression]) {
+        self.begin_scope();
+        self.predeclare(body);
+        self.resolve_body(body);
+        self.end_scope();
+    }
+
+    // Returns the variables of enclosing scopes the body uses before they are declared.
+    fn resolve_function(&mut self, parameters: &[Identifier], body: &[StatementOrExpression]) -> Vec<EarlyUse> {
+        self.function_depth += 1;
+        self.early_uses.push(Vec::new());
+
+        // parameters and the body share a scope
+        self.begin_scope();
+        for param in parameters {
+            self.declare(param, false);
+        }
+        self.predeclare(body);
+        self.resolve_body(body);
+        self.end_scope();
+
+        self.function_depth -= 1;
+        self.early_uses.pop().expect("pushed above")
+    }
+
+    // Calling the function in the variable `name` right away, at `span`, runs
+    // its body now: everything it uses has to be declared by this point.
+    fn check_call(&mut self, name: &str, span: Span) {
+        let Some(scope) = self.lookup(name) else {
+            return;
+        };
+
+        let mut errors = Vec::new();
+        for early in &self.scopes[scope].variables[name].early_uses {
+            let target = &self.scopes[early.scope];
+            // a call from inside another function runs whenever that one does
+            if target.function_depth != self.function_depth {
+                continue;
+            }
+
+            let variable = &target.variables[&early.name];
+            let fallback = self.lookup_below(&early.name, early.scope).is_some() || self.env.get(&early.name).is_some();
+            if !variable.declared && !fallback {
+                errors.push(ResolveError::UsedBeforeDeclaration {
+                    name: early.name.clone(),
+                    span,
+                    used: early.span,
+                    declared: variable.span,
+                });
+            }
+        }
+        self.resolution.errors.extend(errors);
+    }
+
+    fn resolve_stmt(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::Program(p) => self.resolve_body(&p.body),
+            Statement::VariableDecleration(var) => {
+                let early_uses = match &var.value {
+                    Some(Expression::Function(f)) => self.resolve_function(&f.parameters, &f.body),
+                    Some(value) => {
+                        self.resolve_expr(value);
+                        Vec::new()
+                    },
+                    None => Vec::new(),
+                };
+                self.declare(&var.identifier, var.constant);
+                if let Some(variable) = self.declared(&var.identifier) {
+                    variable.early_uses = early_uses;
+                }
+            },
+            Statement::FunctionDeclaration(decl) => {
+                self.declare(&decl.name, false);
+                let early_uses = self.resolve_function(&decl.parameters, &decl.body);
+                if let Some(variable) = self.declared(&decl.name) {
+                    variable.early_uses = early_uses;
+                }
+            },
+            Statement::Return(ret) => {
+                if let Some(value) = &ret.value {
+                    self.resolve_expr(value);
+                }
+            },
+            Statement::Block(block) => self.resolve_block(&block.body),
+            Statement::If(stmt) => {
+                self.resolve_expr(&stmt.condition);
+                self.resolve_block(&stmt.consequent.body);
+                if let Some(alternate) = &stmt.alternate {
+                    self.resolve_stmt(alternate);
+                }
+            },
+            Statement::While(stmt) => {
+                self.resolve_expr(&stmt.condition);
+                self.resolve_block(&stmt.body.body);
+            },
+            Statement::For(stmt) => {
+                // the initializer's variables live for the whole loop
+                self.begin_scope();
+                if let Some(init) = &stmt.init {
+                    self.predeclare(std::slice::from_ref(init.as_ref()));
+                }
+
+                if let Some(init) = &stmt.init {
+                    self.resolve_node(init);
+                }
+                if let Some(condition) = &stmt.condition {
+                    self.resolve_expr(condition);
+                }
+                self.resolve_block(&stmt.body.body);
+                if let Some(update) = &stmt.update {
+                    self.resolve_expr(update);
+                }
+
+                self.end_scope();
+            },
+            Statement::Import(import) => {
+                for name in &import.names {
+                    self.declare_import(name, &import.path);
+                }
+            },
+            Statement::Export(export) => self.resolve_stmt(&export.declaration),
+            Statement::Break(_) | Statement::Continue(_) | Statement::Error(_) => {},
+        }
+    }
+
+    fn resolve_expr(&mut self, expr: &Expression) {
+        match expr {
+            Expression::NumericLiteral(_) | Expression::StringLiteral(_) => {},
+            Expression::Identifier(ident) => self.use_variable(&ident.symbol, ident.span),
+            Expression::Binary(b) => {
+                self.resolve_expr(&b.left);
+                self.resolve_expr(&b.right);
+            },
+            Expression::Unary(u) => self.resolve_expr(&u.argument),
+            Expression::Assignment(a) => {
+                self.resolve_expr(&a.value);
+                match a.assignee.as_ref() {
+                    Expression::Identifier(ident) => self.assign_variable(ident, a.span),
+                    other => self.resolve_expr(other),
+                }
+            },
+            Expression::Property(p) => match &p.value {
+                Some(value) => self.resolve_expr(value),
+                None => self.use_variable(&p.key, p.span),
+            },
+            Expression::ObjectLiteral(obj) => {
+                for prop in obj {
+                    match &prop.value {
+                        Some(value) => self.resolve_expr(value),
+                        // { key } reads the variable `key`
+                        None => self.use_variable(&prop.key, prop.span),
+                    }
+                }
+            },
+            Expression::ArrayLiteral(arr) => {
+                for element in &arr.elements {
+                    self.resolve_expr(element);
+                }
+            },
+            Expression::Member(member) => {
+                self.resolve_expr(&member.object);
+                if member.computed {
+                    self.resolve_expr(&member.property);
+                }
+            },
+            Expression::Call(call) => {
+                self.resolve_expr(&call.caller);
+                for arg in &call.arguments {
+                    self.resolve_expr(arg);
+                }
+                if let Expression::Identifier(ident) = call.caller.as_ref() {
+                    self.check_call(&ident.symbol, call.span);
+                }
+            },
+            Expression::Function(f) => {
+                self.resolve_function(&f.parameters, &f.body);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parser::Parser, Engine};
+
+    fn check(source: &str) -> Resolution {
+        let program = Parser::produce_ast(source).unwrap();
+        resolve(&program, Engine::new().globals())
+    }
+
+    fn errors(source: &str) -> Vec<String> {
+        check(source).errors.iter().map(|e| e.to_string()).collect()
+    }
+
+    fn warnings(source: &str) -> Vec<String> {
+        check(source).warnings.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn undefined_variables() {
+        assert_eq!(errors("let a = b;"), ["Variable b not defined"]);
+        assert_eq!(errors("x = 1;"), ["Variable x not defined"]);
+        assert_eq!(errors("{ let a = 1; } a"), ["Variable a not defined"]);
+        // declared later in the same function
+        assert_eq!(errors("fn f() { let a = b; let b = 1; a }"), ["Variable b not defined"]);
+        assert!(errors("print(len([1]));").is_empty());
+    }
+
+    #[test]
+    fn redefinitions() {
+        assert_eq!(errors("let a = 1; let a = 2;"), ["Variable a already defined"]);
+        assert_eq!(errors("fn f() {} let f = 1;"), ["Variable f already defined"]);
+        assert_eq!(errors("let print = 1;"), ["Variable print already defined"]);
+        assert!(errors("let a = 1; { let a = 2; a }").is_empty());
+    }
+
+    #[test]
+    fn assignments_to_constants() {
+        assert_eq!(errors("const c = 1; c = 2;"), ["Cannot assign to constant c"]);
+        assert_eq!(errors("const c = 1; fn f() { c = 2; }"), ["Cannot assign to constant c"]);
+        assert!(errors("let c = 1; c = 2;").is_empty());
+    }
+
+    #[test]
+    fn repeated_imports() {
+        assert!(errors(r#"import { a } from "./m.avii"; import { a, b } from "./m.avii"; a + b"#).is_empty());
+        assert_eq!(
+            errors(r#"import { a } from "./m.avii"; import { a } from "./n.avii";"#),
+            ["Variable a already defined"]
+        );
+        assert_eq!(errors(r#"let a = 1; import { a } from "./m.avii";"#), ["Variable a already defined"]);
+    }
+
+    #[test]
+    fn functions_see_variables_declared_after_them() {
+        assert!(errors("fn a() { b() } fn b() { 1 } a()").is_empty());
+        assert!(errors("fn f() { later } let later = 1; f()").is_empty());
+        // by the time h runs g, y is declared
+        assert!(errors("{ fn g() { y } fn h() { g() } let y = 2; h() }").is_empty());
+        // falls back to the outer z
+        assert!(errors("let z = 1; { fn f() { z } let r = f(); let z = 5; r }").is_empty());
+    }
+
+    #[test]
+    fn calls_before_a_declaration_the_function_uses() {
+        let resolution = check("{ fn f() { z } let r = f(); let z = 5; r }");
+        assert!(matches!(
+            &resolution.errors[..],
+            [ResolveError::UsedBeforeDeclaration { name, .. }] if name == "z"
+        ));
+
+        assert_eq!(errors("let k = fn () { w }; k(); let w = 4;"), ["Variable w is used before its declaration"]);
+        assert_eq!(errors("fn f() { w = 1; } f(); let w = 4;"), ["Variable w is used before its declaration"]);
+        assert_eq!(
+            errors("fn f() { w } while true { f(); } let w = 4;"),
+            ["Variable w is used before its declaration"]
+        );
+    }
+
+    #[test]
+    fn unused_variables() {
+        assert_eq!(warnings("fn f() { let a = 1; }"), ["Unused variable a"]);
+        assert_eq!(warnings("fn f(x) { 1 }"), ["Unused variable x"]);
+        assert!(warnings("fn f() { let _a = 1; }").is_empty());
+        // the host may still read the top level
+        assert!(warnings("let a = 1;").is_empty());
+    }
+
+    #[test]
+    fn shadowed_variables() {
+        assert_eq!(warnings("let a = 1; { let a = 2; a }"), ["Variable a shadows an outer variable"]);
+        assert_eq!(warnings("fn f(print) { print }"), ["Variable print shadows an outer variable"]);
+        assert!(warnings("let _a = 1; { let _a = 2; _a }").is_empty());
+    }
+}
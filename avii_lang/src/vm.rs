@@ -0,0 +1,332 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::{
+    compiler::{Capture, Chunk, Fallback, FunctionProto, Op},
+    environment::Environment,
+    interpreter::{self, call_function, RuntimeError, RuntimeVal},
+    span::Span,
+};
+
+// A local variable. Closures share it with the frame that declared it; it's
+// empty until the declaration runs.
+type Cell = Rc<RefCell<Option<RuntimeVal>>>;
+
+fn new_cell(value: Option<RuntimeVal>) -> Cell {
+    Rc::new(RefCell::new(value))
+}
+
+// A function compiled for the vm, together with the variables it captured
+pub struct Closure {
+    pub(crate) proto: Rc<FunctionProto>,
+    pub(crate) captures: Rc<[Cell]>,
+    // where the function looks up globals
+    pub(crate) globals: Environment,
+}
+
+impl Closure {
+    pub fn name(&self) -> Option<&str> {
+        self.proto.name.as_deref()
+    }
+}
+
+impl std::fmt::Debug for Closure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let params: Vec<&str> = self.proto.parameters.iter().map(|p| p.symbol.as_str()).collect();
+        write!(f, "Function({}({}))", self.name().unwrap_or("<anonymous>"), params.join(", "))
+    }
+}
+
+struct Frame {
+    chunk: Rc<Chunk>,
+    captures: Rc<[Cell]>,
+    globals: Environment,
+    slots: Vec<Cell>,
+    ip: usize,
+    // where this call's values start on the stack
+    base: usize,
+}
+
+struct Vm {
+    stack: Vec<RuntimeVal>,
+    frames: Vec<Frame>,
+}
+
+/// Runs a compiled program, with `env` holding its globals.
+pub fn run(chunk: &Rc<Chunk>, env: &mut Environment) -> Result<RuntimeVal, RuntimeError> {
+    let frame = Frame {
+        chunk: chunk.clone(),
+        captures: Rc::from(Vec::new()),
+        globals: env.clone(),
+        slots: (0..chunk.slots).map(|_| new_cell(None)).collect(),
+        ip: 0,
+        base: 0,
+    };
+
+    let mut vm = Vm { stack: Vec::new(), frames: vec![frame] };
+    vm.execute_counted(env)
+}
+
+/// Calls a closure with already evaluated arguments.
+pub(crate) fn call(closure: &Closure, args: Vec<RuntimeVal>) -> Result<RuntimeVal, RuntimeError> {
+    let mut vm = Vm { stack: Vec::new(), frames: Vec::new() };
+    let depth = closure.globals.budget().call_depth();
+    vm.push_frame(closure, args)?;
+    let result = vm.execute();
+    closure.globals.budget().set_call_depth(depth);
+    result
+}
+
+impl Vm {
+    fn push_frame(&mut self, closure: &Closure, args: Vec<RuntimeVal>) -> Result<(), RuntimeError> {
+        closure.globals.budget().enter_call(Span::default())?;
+
+        // parameters take the first slots, missing arguments are null
+        let parameters = closure.proto.parameters.len();
+        let mut args = args.into_iter();
+        let slots = (0..closure.proto.chunk.slots)
+            .map(|i| match i < parameters {
+                true => new_cell(Some(args.next().unwrap_or(RuntimeVal::NullVal))),
+                false => new_cell(None),
+            })
+            .collect();
+
+        self.frames.push(Frame {
+            chunk: closure.proto.chunk.clone(),
+            captures: closure.captures.clone(),
+            globals: closure.globals.clone(),
+            slots,
+            ip: 0,
+            base: self.stack.len(),
+        });
+        Ok(())
+    }
+
+    // Frames that fail don't return, so the call depth is reset to what it was
+    // before instead of counted down.
+    fn execute_counted(&mut self, env: &Environment) -> Result<RuntimeVal, RuntimeError> {
+        let depth = env.budget().call_depth();
+        let result = self.execute();
+        env.budget().set_call_depth(depth);
+        result
+    }
+
+    fn execute(&mut self) -> Result<RuntimeVal, RuntimeError> {
+        loop {
+            let frame = self.frames.last_mut().expect("a frame is running until the last one returns");
+            let ip = frame.ip;
+            let op = frame.chunk.code[ip];
+            let span = frame.chunk.spans[ip];
+            frame.ip += 1;
+            frame.globals.budget().step(span)?;
+
+            match op {
+                Op::Constant(index) => self.stack.push(frame.chunk.constants[index as usize].clone()),
+                Op::Null => self.stack.push(RuntimeVal::NullVal),
+                Op::Bool(b) => self.stack.push(RuntimeVal::BoolVal(b)),
+                Op::Pop => {
+                    pop(&mut self.stack);
+                },
+
+                Op::GetGlobal(name) => {
+                    let name = &frame.chunk.names[name as usize];
+                    match frame.globals.get(name) {
+                        Some(value) => self.stack.push(value),
+                        None => return Err(RuntimeError::UndefinedVariable(name.clone(), span)),
+                    }
+                },
+                Op::SetGlobal(name) => {
+                    let value = peek(&self.stack).clone();
+                    frame
+                        .globals
+                        .assign(&frame.chunk.names[name as usize], value)
+                        .map_err(|e| e.at(span))?;
+                },
+                Op::DefineGlobal { name, constant } => {
+                    let value = peek(&self.stack).clone();
+                    frame.globals.declare(&frame.chunk.names[name as usize], value, constant, span)?;
+                },
+
+                Op::NewLocal(slot) => frame.slots[slot as usize] = new_cell(None),
+                Op::InitLocal(slot) => {
+                    *frame.slots[slot as usize].borrow_mut() = Some(peek(&self.stack).clone());
+                },
+                Op::GetLocal { slot, name } => {
+                    let value = read(&frame.slots[slot as usize], &frame.chunk.names[name as usize], span)?;
+                    self.stack.push(value);
+                },
+                Op::SetLocal { slot, name } => {
+                    write(&frame.slots[slot as usize], peek(&self.stack), &frame.chunk.names[name as usize], span)?;
+                },
+                Op::GetCapture { index, name } => {
+                    let name = &frame.chunk.names[name as usize];
+                    let value = match live_capture(frame, index) {
+                        Some(index) => read(&frame.captures[index], name, span)?,
+                        None => frame
+                            .globals
+                            .get(name)
+                            .ok_or_else(|| RuntimeError::UndefinedVariable(name.clone(), span))?,
+                    };
+                    self.stack.push(value);
+                },
+                Op::SetCapture { index, name } => {
+                    let name = &frame.chunk.names[name as usize];
+                    match live_capture(frame, index) {
+                        Some(index) if frame.chunk.captures[index].constant => {
+                            return Err(RuntimeError::AssignToConstant { name: name.clone(), span, declared: None });
+                        },
+                        Some(index) => write(&frame.captures[index], peek(&self.stack), name, span)?,
+                        None => {
+                            frame.globals.assign(name, peek(&self.stack).clone()).map_err(|e| e.at(span))?;
+                        },
+                    }
+                },
+
+                Op::Binary(op) => {
+                    let right = pop(&mut self.stack);
+                    let left = pop(&mut self.stack);
+                    let result = interpreter::eval_binary_expr(left, right, op.symbol()).map_err(|e| match e {
+                        RuntimeError::DivisionByZero(_) => e.at(frame.chunk.divisors.get(&ip).copied().unwrap_or(span)),
+                        _ => e.at(span),
+                    })?;
+                    frame.globals.budget().allocate_value(&result, span)?;
+                    self.stack.push(result);
+                },
+                Op::Not => {
+                    let value = pop(&mut self.stack);
+                    self.stack.push(RuntimeVal::BoolVal(!value.is_truthy()));
+                },
+                Op::Negate => {
+                    let value = pop(&mut self.stack);
+                    self.stack.push(interpreter::eval_unary_op("-", value, span)?);
+                },
+
+                Op::Jump(target) => frame.ip = target as usize,
+                Op::JumpIfFalse(target) => {
+                    if !pop(&mut self.stack).is_truthy() {
+                        frame.ip = target as usize;
+                    }
+                },
+                Op::JumpIfTrue(target) => {
+                    if pop(&mut self.stack).is_truthy() {
+                        frame.ip = target as usize;
+                    }
+                },
+
+                Op::Object(count) => {
+                    let entries = self.stack.split_off(self.stack.len() - 2 * count as usize);
+                    let mut map = HashMap::new();
+                    for pair in entries.chunks(2) {
+                        map.insert(pair[0].to_string(), pair[1].clone());
+                    }
+                    let object = RuntimeVal::object(map);
+                    frame.globals.budget().allocate_value(&object, span)?;
+                    self.stack.push(object);
+                },
+                Op::Array(count) => {
+                    let elements = self.stack.split_off(self.stack.len() - count as usize);
+                    let array = RuntimeVal::array(elements);
+                    frame.globals.budget().allocate_value(&array, span)?;
+                    self.stack.push(array);
+                },
+                Op::Closure(index) => {
+                    let proto = frame.chunk.functions[index as usize].clone();
+                    let captures = proto
+                        .captures
+                        .iter()
+                        .map(|capture| match capture {
+                            Capture::Local(slot) => frame.slots[*slot as usize].clone(),
+                            Capture::Capture(index) => frame.captures[*index as usize].clone(),
+                        })
+                        .collect();
+
+                    self.stack.push(RuntimeVal::Closure(Rc::new(Closure {
+                        proto,
+                        captures,
+                        globals: frame.globals.clone(),
+                    })));
+                },
+
+                Op::GetProperty { computed, spans } => {
+                    let property = pop(&mut self.stack);
+                    let object = pop(&mut self.stack);
+                    let spans = frame.chunk.members[spans as usize];
+                    self.stack.push(interpreter::get_property(object, property, computed, spans)?);
+                },
+                Op::SetProperty { spans, .. } => {
+                    let property = pop(&mut self.stack);
+                    let object = pop(&mut self.stack);
+                    let value = pop(&mut self.stack);
+                    let spans = frame.chunk.members[spans as usize];
+                    self.stack.push(interpreter::set_property(object, property, value, spans, frame.globals.budget())?);
+                },
+
+                Op::Call(count) => {
+                    let args = self.stack.split_off(self.stack.len() - count as usize);
+                    let callee = pop(&mut self.stack);
+
+                    match callee {
+                        // script functions run on this vm instead of recursing
+                        RuntimeVal::Closure(closure) => self.push_frame(&closure, args).map_err(|e| e.at(span))?,
+                        other => {
+                            let mut globals = frame.globals.clone();
+                            let value = call_function(other, args, &mut globals).map_err(|e| e.at(span))?;
+                            self.stack.push(value);
+                        },
+                    }
+                },
+                Op::Return => {
+                    let value = pop(&mut self.stack);
+                    let frame = self.frames.pop().expect("returning from a running frame");
+                    self.stack.truncate(frame.base);
+
+                    if self.frames.is_empty() {
+                        return Ok(value);
+                    }
+                    frame.globals.budget().exit_call();
+                    self.stack.push(value);
+                },
+
+                Op::Fail(index) => return Err(frame.chunk.errors[index as usize].clone()),
+            }
+        }
+    }
+}
+
+// the compiler guarantees every instruction finds the values it needs on the stack
+fn pop(stack: &mut Vec<RuntimeVal>) -> RuntimeVal {
+    stack.pop().expect("value stack underflow")
+}
+
+fn peek(stack: &[RuntimeVal]) -> &RuntimeVal {
+    stack.last().expect("value stack underflow")
+}
+
+// The capture that captures[index] stands for right now: itself once its
+// variable is declared, otherwise what it falls back to. None for a global.
+fn live_capture(frame: &Frame, mut index: u32) -> Option<usize> {
+    loop {
+        let declared = frame.captures[index as usize].borrow().is_some();
+        match frame.chunk.captures[index as usize].fallback {
+            Some(Fallback::Capture(next)) if !declared => index = next,
+            Some(Fallback::Global) if !declared => return None,
+            _ => return Some(index as usize),
+        }
+    }
+}
+
+fn read(cell: &Cell, name: &str, span: Span) -> Result<RuntimeVal, RuntimeError> {
+    match &*cell.borrow() {
+        Some(value) => Ok(value.clone()),
+        None => Err(RuntimeError::UndefinedVariable(name.to_string(), span)),
+    }
+}
+
+fn write(cell: &Cell, value: &RuntimeVal, name: &str, span: Span) -> Result<(), RuntimeError> {
+    match &mut *cell.borrow_mut() {
+        Some(current) => {
+            *current = value.clone();
+            Ok(())
+        },
+        None => Err(RuntimeError::UndefinedVariable(name.to_string(), span)),
+    }
+}
@@ -0,0 +1,155 @@
+// The bytecode vm has to behave exactly like the tree walking interpreter:
+// every script here runs on both, and both have to give the expected value or
+// error.
+
+use avii_lang::{interpreter::RuntimeVal, Engine};
+
+type Expected = Result<RuntimeVal, &'static str>;
+
+fn scripts() -> Vec<(&'static str, &'static str, Expected)> {
+    vec![
+        ("arithmetic", "1 + 2 * 3 - 8 / 4 % 3", Ok(number(5.0))),
+        ("strings", r#"let s = "a" + 1; s + "b""#, Ok(string("a1b"))),
+        ("comparison", "1 < 2 && 2 <= 2 && !(3 > 4) && 4 >= 4 && 1 != 2", Ok(boolean(true))),
+        (
+            "logical operators",
+            "let calls = 0; fn f() { calls = calls + 1; true } [false && f(), true || f(), null || f(), calls]",
+            Ok(array(vec![boolean(false), boolean(true), boolean(true), number(1.0)])),
+        ),
+        ("globals", "let x = 1; const y = 2; x = x + y; x", Ok(number(3.0))),
+        ("blocks", "let x = 1; { let x = 2; x = 3; } x", Ok(number(1.0))),
+        ("if else", "let x = 5; if x > 3 { 1 } else if x > 1 { 2 } else { 3 }", Ok(number(1.0))),
+        (
+            "while",
+            "let i = 0; let sum = 0; while i < 10 { i = i + 1; if i % 2 == 0 { continue; } sum = sum + i; } sum",
+            Ok(number(25.0)),
+        ),
+        (
+            "for with break",
+            "let last = 0; for (let i = 0; i < 100; i = i + 1) { if i == 7 { break; } last = i; } last",
+            Ok(number(6.0)),
+        ),
+        ("recursion", "fn fib(n) { if n < 2 { return n; } fib(n - 1) + fib(n - 2) } fib(15)", Ok(number(610.0))),
+        ("hoisting", "fn a() { b() } fn b() { 42 } a()", Ok(number(42.0))),
+        (
+            "counter closure",
+            "fn counter() { let n = 0; fn () { n = n + 1; n } } let c = counter(); c(); c(); c()",
+            Ok(number(3.0)),
+        ),
+        (
+            "closures share variables",
+            "fn pair() { let n = 0; [fn () { n = n + 1; }, fn () { n }] } let p = pair(); p[0](); p[0](); p[1]()",
+            Ok(number(2.0)),
+        ),
+        (
+            "closures per loop iteration",
+            "let fs = []; for (let i = 0; i < 3; i = i + 1) { let j = i; fs.push(fn () { j }); } fs[0]() + fs[2]()",
+            Ok(number(2.0)),
+        ),
+        (
+            "nested closures",
+            "fn outer(a) { fn middle(b) { fn inner(c) { a + b + c } inner } middle } outer(1)(2)(3)",
+            Ok(number(6.0)),
+        ),
+        ("capture before declaration", "let z = 1; { fn f() { z } let r = f(); let z = 5; r }", Ok(number(1.0))),
+        ("capture after declaration", "let z = 1; { fn f() { z } let z = 5; f() }", Ok(number(5.0))),
+        (
+            "capture before declaration in a function",
+            "fn g() { let z = 1; { fn f() { z } let r = f(); let z = 5; [r, f()] } } g()",
+            Ok(array(vec![number(1.0), number(5.0)])),
+        ),
+        (
+            "capture through two functions",
+            "fn g() { let z = 1; { fn f() { fn () { z } } let r = f()(); let z = 5; [r, f()()] } } g()",
+            Ok(array(vec![number(1.0), number(5.0)])),
+        ),
+        ("assign before declaration", "let z = 1; { fn f() { z = 2; } f(); let z = 5; } z", Ok(number(2.0))),
+        (
+            "undeclared capture",
+            "{ fn f() { z } let r = f(); let z = 5; r }",
+            Err("Variable z is used before its declaration"),
+        ),
+        // the resolver only follows direct calls, this one fails when it runs
+        (
+            "undeclared capture through another name",
+            "{ fn f() { z } let g = f; let r = g(); let z = 5; r }",
+            Err("Variable z not defined"),
+        ),
+        (
+            "objects",
+            "let o = { a: 1, b: { c: 2 } }; o.b.c = o.a + 10; o.d = 4; o",
+            Ok(object(&[("a", number(1.0)), ("b", object(&[("c", number(11.0))])), ("d", number(4.0))])),
+        ),
+        (
+            "arrays",
+            "let a = [1, 2, 3]; a[1] = 20; a.push(4); [len(a), a.slice(1, 3), a.map(fn (x) { x * 2 })]",
+            Ok(array(vec![number(4.0), array(vec![number(20.0), number(3.0)]), array(vec![number(2.0), number(40.0), number(6.0), number(8.0)])])),
+        ),
+        ("reduce", "[1, 2, 3, 4].reduce(fn (acc, x) { acc + x }, 10)", Ok(number(20.0))),
+        ("function values", "let f = fn (x) { x * 2 }; let g = f; g(21)", Ok(number(42.0))),
+        ("undefined variable", "let a = 1; b + a", Err("Variable b not defined")),
+        ("division by zero", "let a = 0; 10 / a", Err("Division by zero")),
+        ("remainder by zero", "let a = 0; 10 % a", Err("Division by zero")),
+        ("assign to constant", "const c = 1; c = 2;", Err("Cannot assign to constant c")),
+        ("assign to captured constant", "const c = 1; fn f() { c = 2; } f()", Err("Cannot assign to constant c")),
+        ("already defined", "let a = 1; let a = 2;", Err("Variable a already defined")),
+        ("not callable", "let x = 1; x()", Err("Value of type number is not callable")),
+        ("missing property", "let o = {}; o.a.b", Err("Property a does not exist")),
+        ("index out of bounds", "[1, 2][5]", Err("Index 5 out of bounds for length 2")),
+        ("break outside loop", "fn f() { break; } f()", Err("Cannot break outside of a loop")),
+        ("return at top level", "return 1;", Err("Cannot return outside of a function")),
+    ]
+}
+
+fn number(n: f64) -> RuntimeVal {
+    RuntimeVal::NumberVal(n)
+}
+
+fn boolean(b: bool) -> RuntimeVal {
+    RuntimeVal::BoolVal(b)
+}
+
+fn string(s: &str) -> RuntimeVal {
+    RuntimeVal::string(s)
+}
+
+fn array(values: Vec<RuntimeVal>) -> RuntimeVal {
+    RuntimeVal::array(values)
+}
+
+fn object(properties: &[(&str, RuntimeVal)]) -> RuntimeVal {
+    RuntimeVal::object(properties.iter().map(|(key, value)| (key.to_string(), value.clone())).collect())
+}
+
+fn run(source: &str, use_vm: bool) -> Result<RuntimeVal, String> {
+    let mut engine = match use_vm {
+        true => Engine::new().with_vm(),
+        false => Engine::new(),
+    };
+    engine.eval(source).map_err(|e| e.to_string())
+}
+
+// Arrays and objects compare by reference in scripts, here they compare by
+// what they print as. The type name tells `1` and `"1"` apart.
+fn matches(result: &Result<RuntimeVal, String>, expected: &Expected) -> bool {
+    match (result, expected) {
+        (Ok(value), Ok(expected)) => value.type_name() == expected.type_name() && value.to_string() == expected.to_string(),
+        (Err(error), Err(expected)) => error == expected,
+        _ => false,
+    }
+}
+
+#[test]
+fn both_backends_give_the_expected_result() {
+    let mut failures = Vec::new();
+    for (name, source, expected) in scripts() {
+        for (backend, use_vm) in [("tree walker", false), ("vm", true)] {
+            let result = run(source, use_vm);
+            if !matches(&result, &expected) {
+                failures.push(format!("{} on the {}:\n  expected: {:?}\n  got:      {:?}", name, backend, expected, result));
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "unexpected results for\n{}", failures.join("\n"));
+}
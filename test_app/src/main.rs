@@ -6,31 +6,60 @@
 use std::io::Write;
 
 use avii_lang::{
-    ast::{Statement, StatementOrExpression},
-    environment::Environment,
-    interpreter,
+    capabilities::Capabilities,
+    diagnostics::Diagnostic,
     parser::Parser,
+    resolver,
+    Engine,
 };
 
 fn main() {
 
-    // allow passing in a filename as argument for parsing
-    let args: Vec<String> = std::env::args().collect();
+    // allow passing in a filename as argument for parsing, `--vm` picks the bytecode backend
+    let mut args: Vec<String> = std::env::args().collect();
+    let use_vm = args.iter().any(|arg| arg == "--vm");
+    args.retain(|arg| arg != "--vm");
+
+    // scripts run from the command line are trusted with everything
+    let engine = Engine::with_capabilities(Capabilities::all());
+    let mut engine = match use_vm {
+        true => engine.with_vm(),
+        false => engine,
+    };
+
     if args.len() > 1 {
         let filename = &args[1];
 
         // read the file
         let source_code = std::fs::read_to_string(filename).expect("Could not read file");
 
-        // parse the file
-        let program = Parser::produce_ast(&source_code);
+        // parse the file, reporting every syntax error at once
+        let (program, errors) = Parser::produce_ast_recovering(&source_code);
+        if !errors.is_empty() {
+            for e in &errors {
+                eprint!("{}", Diagnostic::from(e).render(filename, &source_code));
+            }
+            std::process::exit(1);
+        }
 
-        // run the program
-        let mut env = Environment::new().with_default_scope();
-        let output = interpreter::evaluate(StatementOrExpression::Statement(Statement::Program(program)), &mut env);
+        // warn about suspicious variables before running anything, the engine
+        // checks for errors itself
+        let resolution = resolver::resolve(&program, engine.globals());
+        for w in &resolution.warnings {
+            eprint!("{}", Diagnostic::from(w).render(filename, &source_code));
+        }
+
+        // run the program, loading the modules it imports
+        let output = engine.run_file(filename);
 
         // print the output
-        print!("{:#?}", output);
+        match output {
+            Ok(value) => print!("{:#?}", value),
+            Err(e) => {
+                eprint!("{}", e.render(filename, &source_code));
+                std::process::exit(1);
+            }
+        }
 
         // exit
         std::process::exit(0);
@@ -41,21 +70,16 @@ fn main() {
         env!("CARGO_PKG_NAME"),
         env!("CARGO_PKG_VERSION")
     );
-    
-    let mut env = Environment::new().with_default_scope();
 
     loop {
         let mut input = String::new();
         print!("> ");
         std::io::stdout().flush().unwrap();
         std::io::stdin().read_line(&mut input).unwrap();
-        let ast = Parser::produce_ast(&input);
-
-        let result = interpreter::evaluate(
-            StatementOrExpression::Statement(Statement::Program(ast)),
-            &mut env,
-        );
 
-        println!("{:#?}", result);
+        match engine.eval(&input) {
+            Ok(value) => println!("{:#?}", value),
+            Err(e) => print!("{}", e.render("<repl>", &input)),
+        }
     }
 }